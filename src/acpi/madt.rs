@@ -0,0 +1,151 @@
+
+//! The Multiple APIC Description Table, signature "APIC".
+//!
+//! Lists the local APIC address, the I/O APICs and how ISA IRQs are
+//! wired to them when it's not one to one.
+
+use super::Table;
+
+const MAX_IO_APICS: usize = 4;
+const MAX_OVERRIDES: usize = 16;
+
+// Entry types.
+const LOCAL_APIC_ENTRY: u8 = 0;
+const IO_APIC_ENTRY: u8 = 1;
+const INTERRUPT_OVERRIDE_ENTRY: u8 = 2;
+const LOCAL_APIC_ADDRESS_ENTRY: u8 = 5;
+
+/// Flag saying there's also a pair of 8259 PICs.
+const PCAT_COMPAT: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't on the global system interrupt of the same
+/// number, or doesn't have the ISA polarity and trigger mode.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+impl InterruptOverride {
+    fn new(irq: u8, gsi: u32, flags: u16) -> InterruptOverride
+    {
+        // 0 is "conforms to the bus", which is high and edge for ISA.
+        InterruptOverride {
+            irq: irq,
+            gsi: gsi,
+            polarity: if flags & 0b11 == 0b11 {
+                Polarity::ActiveLow
+            } else {
+                Polarity::ActiveHigh
+            },
+            trigger: if (flags >> 2) & 0b11 == 0b11 {
+                TriggerMode::Level
+            } else {
+                TriggerMode::Edge
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub has_legacy_pics: bool,
+    /// Enabled processors.
+    pub processors: usize,
+    pub io_apics: [Option<IoApicEntry>; MAX_IO_APICS],
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+impl Madt {
+    pub fn parse(table: &Table) -> Madt
+    {
+        let mut madt = Madt {
+            local_apic_address: table.u32(36) as u64,
+            has_legacy_pics: table.u32(40) & PCAT_COMPAT != 0,
+            processors: 0,
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_OVERRIDES],
+        };
+
+        let mut offset = 44;
+        while offset + 2 <= table.len() {
+            let length = table.u8(offset + 1) as usize;
+            if length < 2 || offset + length > table.len() {
+                println!("MADT: bad entry at {:#x}", offset);
+                break;
+            }
+            match table.u8(offset) {
+                LOCAL_APIC_ENTRY => {
+                    if table.u32(offset + 4) & 1 != 0 {
+                        madt.processors += 1;
+                    }
+                }
+                IO_APIC_ENTRY => {
+                    let entry = IoApicEntry {
+                        id: table.u8(offset + 2),
+                        address: table.u32(offset + 4),
+                        gsi_base: table.u32(offset + 8),
+                    };
+                    match madt.io_apics.iter_mut().find(|a| a.is_none()) {
+                        Some(slot) => *slot = Some(entry),
+                        None => println!("MADT: too many I/O APICs, ignoring {}", entry.id),
+                    }
+                }
+                INTERRUPT_OVERRIDE_ENTRY => {
+                    // Bus 0 is ISA, it's the only one defined.
+                    let entry = InterruptOverride::new(table.u8(offset + 3),
+                                                       table.u32(offset + 4),
+                                                       table.u16(offset + 8));
+                    match madt.overrides.iter_mut().find(|o| o.is_none()) {
+                        Some(slot) => *slot = Some(entry),
+                        None => println!("MADT: too many overrides, ignoring IRQ {}", entry.irq),
+                    }
+                }
+                LOCAL_APIC_ADDRESS_ENTRY => {
+                    madt.local_apic_address = table.u64(offset + 4);
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        madt
+    }
+
+    /// Where ISA `irq` ends up, identity mapped with ISA signalling
+    /// unless there's an override.
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride
+    {
+        self.overrides.iter()
+            .filter_map(|o| *o)
+            .find(|o| o.irq == irq)
+            .unwrap_or(InterruptOverride {
+                irq: irq,
+                gsi: irq as u32,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Edge,
+            })
+    }
+}
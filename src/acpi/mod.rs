@@ -0,0 +1,212 @@
+
+//! Just enough ACPI to find the tables describing the hardware.
+//!
+//! Tables are mapped through the MMIO window as that's the only way to
+//! reach arbitrary physical memory, but cached as they're in RAM. They're
+//! only read during boot, unmap them with `unmap` once they've been read.
+
+pub mod madt;
+
+use memory::FrameAllocator;
+use memory::mmio::{self, MmioRegion};
+use memory::paging::{Mapper, MapError, PhysicalAddress};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No RSDP in the BIOS areas.
+    NoRsdp,
+    BadChecksum,
+    /// A table's length is shorter than its header.
+    BadLength,
+    Map(MapError),
+}
+
+impl From<MapError> for AcpiError {
+    fn from(error: MapError) -> AcpiError
+    {
+        AcpiError::Map(error)
+    }
+}
+
+/// Length of the header every system description table starts with.
+pub const SDT_HEADER_LENGTH: usize = 36;
+
+/// The root table, RSDT has 32-bit entries and XSDT has 64-bit ones.
+#[derive(Clone, Copy)]
+struct Root {
+    table: Table,
+    entry_size: usize,
+}
+
+static ROOT: Mutex<Option<Root>> = Mutex::new(None);
+
+/// A mapped ACPI table. Reads are little endian and unaligned.
+#[derive(Clone, Copy)]
+pub struct Table {
+    region: MmioRegion,
+}
+
+impl Table {
+    pub fn len(&self) -> usize
+    {
+        self.region.size()
+    }
+
+    pub fn u8(&self, offset: usize) -> u8
+    {
+        unsafe { self.region.read::<u8>(offset) }
+    }
+
+    pub fn u16(&self, offset: usize) -> u16
+    {
+        self.u8(offset) as u16 | (self.u8(offset + 1) as u16) << 8
+    }
+
+    pub fn u32(&self, offset: usize) -> u32
+    {
+        self.u16(offset) as u32 | (self.u16(offset + 2) as u32) << 16
+    }
+
+    pub fn u64(&self, offset: usize) -> u64
+    {
+        self.u32(offset) as u64 | (self.u32(offset + 4) as u64) << 32
+    }
+
+    pub fn signature(&self) -> [u8; 4]
+    {
+        [self.u8(0), self.u8(1), self.u8(2), self.u8(3)]
+    }
+
+    /// ACPI checksums make the bytes sum to 0.
+    fn checksum_ok(&self, offset: usize, length: usize) -> bool
+    {
+        (0..length).fold(0u8, |sum, i| sum.wrapping_add(self.u8(offset + i))) == 0
+    }
+}
+
+/// Map `length` bytes of physical memory as a table.
+fn map<A>(physical: PhysicalAddress, length: usize, mapper: &mut Mapper, allocator: &mut A)
+          -> Result<Table, AcpiError>
+    where A: FrameAllocator
+{
+    let region = try!(mmio::map_ram(physical, length, mapper, allocator));
+    Ok(Table { region: region })
+}
+
+/// Unmap a table once it's been read.
+pub fn unmap(table: Table, mapper: &mut Mapper)
+{
+    mmio::unmap(table.region, mapper);
+}
+
+/// Map a system description table, header first to find its length.
+fn map_sdt<A>(physical: PhysicalAddress, mapper: &mut Mapper, allocator: &mut A)
+              -> Result<Table, AcpiError>
+    where A: FrameAllocator
+{
+    let header = try!(map(physical, SDT_HEADER_LENGTH, mapper, allocator));
+    let length = header.u32(4) as usize;
+    unmap(header, mapper);
+    if length < SDT_HEADER_LENGTH {
+        return Err(AcpiError::BadLength);
+    }
+
+    let table = try!(map(physical, length, mapper, allocator));
+    if !table.checksum_ok(0, table.len()) {
+        unmap(table, mapper);
+        return Err(AcpiError::BadChecksum);
+    }
+    Ok(table)
+}
+
+/// Look for the RSDP signature on the 16 byte boundaries of an area.
+fn search_rsdp<A>(start: PhysicalAddress, length: usize, mapper: &mut Mapper, allocator: &mut A)
+                  -> Result<Option<Table>, AcpiError>
+    where A: FrameAllocator
+{
+    let area = try!(map(start, length, mapper, allocator));
+    let found = (0..length).step_by(16).find(|&offset| {
+        let matches = b"RSD PTR ".iter().enumerate()
+            .all(|(i, &byte)| area.u8(offset + i) == byte);
+        matches && area.checksum_ok(offset, 20)
+    });
+    unmap(area, mapper);
+    match found {
+        Some(offset) => map(start + offset, 36, mapper, allocator).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Find the RSDP in the first KiB of the EBDA or the BIOS ROM area.
+fn find_rsdp<A>(mapper: &mut Mapper, allocator: &mut A) -> Result<Table, AcpiError>
+    where A: FrameAllocator
+{
+    // The BIOS data area has the EBDA's segment at 0x40e.
+    let bda = try!(map(0x400, 0x100, mapper, allocator));
+    let ebda = (bda.u16(0x0e) as usize) << 4;
+    unmap(bda, mapper);
+    if ebda != 0 {
+        if let Some(rsdp) = try!(search_rsdp(ebda, 1024, mapper, allocator)) {
+            return Ok(rsdp);
+        }
+    }
+    try!(search_rsdp(0xe0000, 0x20000, mapper, allocator)).ok_or(AcpiError::NoRsdp)
+}
+
+/// Find the root table. Has to be called before `find_table`.
+pub fn init<A>(mapper: &mut Mapper, allocator: &mut A) -> Result<(), AcpiError>
+    where A: FrameAllocator
+{
+    let rsdp = try!(find_rsdp(mapper, allocator));
+    let (rsdt, xsdt) = (rsdp.u32(16) as usize, rsdp.u64(24) as usize);
+    let revision = rsdp.u8(15);
+    unmap(rsdp, mapper);
+
+    let root = if revision >= 2 && xsdt != 0 {
+        Root {
+            table: try!(map_sdt(xsdt, mapper, allocator)),
+            entry_size: 8,
+        }
+    } else {
+        Root {
+            table: try!(map_sdt(rsdt, mapper, allocator)),
+            entry_size: 4,
+        }
+    };
+    *ROOT.lock() = Some(root);
+    Ok(())
+}
+
+/// Find and map the table with the given signature, eg. `b"APIC"`.
+pub fn find_table<A>(signature: &[u8; 4], mapper: &mut Mapper, allocator: &mut A)
+                     -> Option<Table>
+    where A: FrameAllocator
+{
+    let root = match *ROOT.lock() {
+        Some(root) => root,
+        None => return None,
+    };
+
+    // `map_sdt` checked it's at least a header long.
+    let entries = (root.table.len() - SDT_HEADER_LENGTH) / root.entry_size;
+    for i in 0..entries {
+        let offset = SDT_HEADER_LENGTH + i * root.entry_size;
+        let physical = if root.entry_size == 8 {
+            root.table.u64(offset) as usize
+        } else {
+            root.table.u32(offset) as usize
+        };
+        // Only the header to check the signature, most tables aren't wanted.
+        let header = match map(physical, SDT_HEADER_LENGTH, mapper, allocator) {
+            Ok(header) => header,
+            Err(_) => continue,
+        };
+        let found = &header.signature() == signature;
+        unmap(header, mapper);
+        if found {
+            return map_sdt(physical, mapper, allocator).ok();
+        }
+    }
+    None
+}
@@ -1,14 +1,22 @@
 
+%include "memory_layout.inc"
+
 extern long_mode_start
-extern idtr
         
 global start
 global gdt.kernel_code        
-
-section .text
+global gdtr
+global p4_table
+global stack_top
+global stack_bottom
+
+;; Everything in .boot.text runs before we're in the higher half so
+;; it's linked at its physical address. Anything outside of it has to
+;; be referred to by its physical address (symbol - KERNEL_OFFSET).
+section .boot.text exec
 bits 32
 start:
-        mov  esp, stack_top     ; Set up stack pointer
+        mov  esp, stack_top - KERNEL_OFFSET ; Set up stack pointer
         mov  edi, ebx           ; Save multiboot address
         call check_multiboot
         call check_cpuid
@@ -18,11 +26,10 @@ start:
         call enable_paging
         call set_up_SSE
 
-        ;; Load the 64-bit GDT
-        lgdt [gdtr]
+        ;; Load the 64-bit GDT (at it's physical address for now)
+        lgdt [boot_gdtr]
 
-        ; Load the idt register.
-        ; lidt [idtr]
+        ;; The IDT is loaded later by Rust (irq::initialize_interrupts)
 
         ;; Load the selectors
         mov ax, gdt.kernel_data
@@ -30,7 +37,7 @@ start:
         mov ds, ax              ; data selector
         mov es, ax              ; extra selector
 
-        jmp gdt.kernel_code:long_mode_start
+        jmp gdt.kernel_code:higher_half_trampoline
 
         ; Oh dear
         hlt
@@ -105,23 +112,26 @@ check_long_mode:
         jmp error
 
 ;; Function: set_up_page_tables
-;; Set's up the first GiB of Memory with identity paging.
-;; (Virtual RAM maps to physical ram)
+;; Set's up the first GiB of Memory twice. Once with identity paging
+;; (Virtual RAM maps to physical ram), so we survive enabling paging,
+;; and once at KERNEL_OFFSET which is where the kernel is linked.
+;; Both share the same p3 table.
 ;; Also add recursive mapping to the last entry of the p4 table
 set_up_page_tables:
-        mov eax, p4_table       ; Recursively map p4 to itself in it's last entry.
+        mov eax, p4_table - KERNEL_OFFSET ; Recursively map p4 to itself in it's last entry.
         or  eax, 0b11           ; Present + writable
-        mov [p4_table + 511 * 8] , eax
+        mov [p4_table - KERNEL_OFFSET + 511 * 8] , eax
         
-        ;; Map first p4 entry to p3 
-        mov eax, p3_table
+        ;; Map first p4 entry and the kernel's p4 entry to p3
+        mov eax, p3_table - KERNEL_OFFSET
         or  eax, 0b11           ; Present + writable
-        mov [p4_table], eax
+        mov [p4_table - KERNEL_OFFSET], eax
+        mov [p4_table - KERNEL_OFFSET + KERNEL_P4_INDEX * 8], eax
 
         ;; Map first p3 entry to p2
-        mov eax, p2_table
+        mov eax, p2_table - KERNEL_OFFSET
         or  eax, 0b11            ; Present + writable
-        mov [p3_table], eax
+        mov [p3_table - KERNEL_OFFSET], eax
 
         ;; Map each P2 eantry to a huge 2MiB page
         mov ecx, 0              ; counter
@@ -131,7 +141,7 @@ set_up_page_tables:
         mov eax, 0x200000       ; 2 MiB
         mul ecx                 ; start address of ecx-th page
         or eax, 0b10000011      ; present + writable + huge
-        mov [p2_table + ecx * 8], eax ; map the ecx-th entry
+        mov [p2_table - KERNEL_OFFSET + ecx * 8], eax ; map the ecx-th entry
 
         inc ecx                 ; increase counter
         cmp ecx, 512            ; if counter == 512, all entries have been written
@@ -141,7 +151,7 @@ set_up_page_tables:
 
 enable_paging:
         ;; load P4 to CR3 register (CPU looks for Page Table here)
-        mov eax, p4_table
+        mov eax, p4_table - KERNEL_OFFSET
         mov cr3, eax
 
         ;; enable PAE-flag in CR4 (Physical Address Extension)
@@ -186,6 +196,19 @@ set_up_SSE:
 .no_SSE:
         mov al, "a"
         jmp error
+
+;; The 32-bit far jump can only reach the low half. So we land here
+;; (still identity mapped) and jump to the real long_mode_start.
+bits 64
+higher_half_trampoline:
+        mov rax, long_mode_start
+        jmp rax
+
+;; 32-bit lgdt only takes a 32-bit base, so it needs it's own gdtr
+;; with the physical address of the gdt.
+boot_gdtr:
+        dw gdt.end - 1          ; size
+        dd gdt - KERNEL_OFFSET  ; offset
         
 section .rodata
 
@@ -366,7 +389,7 @@ p3_table:
         resb 4096
 p2_table:
         resb 4096
-p1_table:
+p1_table:                       ; Unused, becomes the stack guard page
         resb 4096
 stack_bottom:
         resb 8192
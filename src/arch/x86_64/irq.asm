@@ -2,31 +2,38 @@
 
 ;; Derived from redox-os
 ;; MIT licenced
-        
-global idtr
-        
+
+global isr_stub_table
+
 extern interrupt_handler
-extern gdt.kernel_code
 
 section .text
 bits 64
+; The kernel is linked above 4GiB, so absolute 32-bit addressing won't reach.
+default rel
 
+;; Stack layout when .handle calls interrupt_handler, lowest address
+;; first. Must match InterruptStack in irq/isr.rs
+;;
+;;   rax rbx rcx rdx rdi rsi r8 .. r15 rbp   pushed by .handle
+;;   vector                                  pushed by the stub
+;;   error code                              CPU or stub (0)
+;;   rip cs rflags rsp ss                    pushed by the CPU
 isr:
-;; Put the interrupt number into the address .entry
-;; then jump to .handle
-.int0:
-        mov [.entry], dword 0
-        jmp .handle
-.int1:
-%assign i 1
-%rep 255        
-        mov [.entry], dword i
+;; Push a dummy error code for vectors that don't get one from the
+;; CPU (everything except 8, 10-14, 17, 21, 29 and 30), then push the
+;; vector number and jump to .handle
+%assign i 0
+%rep 256
+.int %+ i:
+%if !(i == 8 || (i >= 10 && i <= 14) || i == 17 || i == 21 || i == 29 || i == 30)
+        push 0
+%endif
+        push i
         jmp .handle
 %assign i (i+1)
 %endrep
 
-.entry:
-        dq 0
 .handle:
         push rbp
         push r15
@@ -45,49 +52,51 @@ isr:
         push rax
 
         ; Argumements
-        mov rdi, qword [.entry] ; interrupt_number: u8,
-        mov rsi, rsp            ; stack_address: u64
+        mov rdi, rsp            ; stack: &mut InterruptStack
 
         ; SYS-V requires 16 byte alignment of stack pointer
         ; This will round it downwards to the nearest multiple of 16.
+        ; rbp is callee saved so it survives the call.
         mov rbp, rsp
         and rsp, 0xfffffffffffffff0
-        
+        ; And the direction flag to be clear
+        cld
+        ; Handlers mustn't inherit AC from an interrupted
+        ; with_user_access, iretq gives it back. This is clac, which
+        ; would #UD on CPUs without SMAP.
+        pushfq
+        and qword [rsp], ~(1 << 18)
+        popfq
+
         call interrupt_handler
 
-        push rax
-        push rbx
-        push rcx
-        push rdx
-        push rdi
-        push rsi
-        push r8
-        push r9
-        push r10
-        push r11
-        push r12
-        push r13
-        push r14
-        push r15
-        push rbp
-        
+        mov rsp, rbp
+        pop rax
+        pop rbx
+        pop rcx
+        pop rdx
+        pop rdi
+        pop rsi
+        pop r8
+        pop r9
+        pop r10
+        pop r11
+        pop r12
+        pop r13
+        pop r14
+        pop r15
+        pop rbp
+
+        ; Drop the vector and error code
+        add rsp, 16
         iretq
 
+;; The stubs aren't all the same length (the jmp can be short or
+;; near) so the IDT is built from this table. See irq/idt.rs
 section .rodata
-idtr:
-        dw (idt.end - idt) + 1
-        dq idt
-idt:
+isr_stub_table:
 %assign i 0
 %rep 256
-;;        dw isr.int0 - (isr.int1 - isr.int0) * i ; offset
-        dw 0
-        dw gdt.kernel_code                      ; gdt section
-        db 0                                    ; 
-        db (0b1110) | (0 << 7)                  ; interrupt64 | present
-        dw 0                                    ;
-        dd 0                                    ; reserved
-        dd 0                                    ;
+        dq isr.int %+ i
 %assign i (i+1)
 %endrep
-.end:
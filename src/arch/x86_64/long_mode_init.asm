@@ -1,25 +1,46 @@
+%include "memory_layout.inc"
+
 global long_mode_start
 
 extern rust_main
+extern gdtr
+extern p4_table
+extern stack_top
         
 section .text
 bits 64
+; The kernel is linked above 4GiB, so absolute 32-bit addressing won't reach.
+default rel
 ;; fn long_mode_start(multiboot_address: usize) -> !
 ;; Returning from long mode would be bonkers.
+;; We're now running in the higher half.
 long_mode_start:
-        ; Assuming multiboot address is in rdi (argument to this function)
+        ; Move the stack and gdt into the higher half as well
+        mov rsp, stack_top
+        lgdt [gdtr]
+
+        ; Drop the identity map, from now on the lower half is free
+        mov qword [p4_table], 0
+        mov rax, cr3
+        mov cr3, rax
+
+        ; Multiboot address is in rdi (argument to this function)
+        ; It's physical so move it into the higher half
+        mov rax, KERNEL_OFFSET
+        add rdi, rax
         ; Put token::Dispenser in rsi (arg2)
         mov rsi, token.frame_token
         ; Call rust
         call rust_main
         
         ;  Print "OS returned!" (which is bad)
+        mov rdx, KERNEL_OFFSET + 0xb8000
         mov rax, 0x4f724f204f534f4f
-        mov [0xb8000], rax
+        mov [rdx], rax
         mov rax, 0x4f724f754f744f65
-        mov [0xb8008], rax
+        mov [rdx + 0x08], rax
         mov rax, 0x4f214f644f654f6e
-        mov [0xb8010], rax
+        mov [rdx + 0x10], rax
         hlt
         
 token:
@@ -0,0 +1,105 @@
+
+//! CPU identification.
+
+/// The four registers `cpuid` returns.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Run `cpuid` with the given leaf (eax) and subleaf (ecx).
+/// The boot code has already checked the instruction exists.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult
+{
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf)
+             :: "volatile");
+    }
+    CpuidResult { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
+
+/// Highest basic leaf supported.
+pub fn max_leaf() -> u32
+{
+    cpuid(0, 0).eax
+}
+
+/// Bit `bit` of ebx in leaf 7 (structured extended features).
+fn leaf7_ebx(bit: u32) -> bool
+{
+    max_leaf() >= 7 && cpuid(7, 0).ebx & (1 << bit) != 0
+}
+
+/// Supervisor Mode Execution Prevention.
+pub fn has_smep() -> bool
+{
+    leaf7_ebx(7)
+}
+
+/// Supervisor Mode Access Prevention.
+pub fn has_smap() -> bool
+{
+    leaf7_ebx(20)
+}
+
+/// There's a local APIC.
+pub fn has_apic() -> bool
+{
+    cpuid(1, 0).edx & (1 << 9) != 0
+}
+
+/// The local APIC supports x2APIC mode.
+pub fn has_x2apic() -> bool
+{
+    cpuid(1, 0).ecx & (1 << 21) != 0
+}
+
+/// Read the time stamp counter.
+pub fn rdtsc() -> u64
+{
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("rdtsc" : "={edx}"(high), "={eax}"(low) ::: "volatile");
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// RFLAGS.IF, interrupts are enabled.
+pub const INTERRUPT_FLAG: u64 = 1 << 9;
+
+pub fn rflags() -> u64
+{
+    let flags: u64;
+    unsafe {
+        asm!("pushfq; popq $0" : "=r"(flags) ::: "volatile");
+    }
+    flags
+}
+
+pub fn interrupts_enabled() -> bool
+{
+    rflags() & INTERRUPT_FLAG != 0
+}
+
+/// Halt until the next interrupt, unless `ready` says there's no need.
+/// `ready` is checked with interrupts off and `sti` only takes effect
+/// after the `hlt`, so a wakeup can't land in between and be missed.
+/// Returns with interrupts enabled.
+pub fn halt_unless<F>(ready: F)
+    where F: FnOnce() -> bool
+{
+    unsafe {
+        asm!("cli" :::: "volatile");
+        if ready() {
+            asm!("sti" :::: "volatile");
+        } else {
+            asm!("sti; hlt" :::: "volatile");
+        }
+    }
+}
@@ -0,0 +1,266 @@
+
+//! Breakpoints and hardware watchpoints.
+//!
+//! `int3` logs where it was hit and carries on, so it can be dropped
+//! in anywhere as a trace point. Watchpoints use the debug registers to
+//! catch accesses to an address, they're reported by the #DB handler.
+//! `Backtrace` follows frame pointers, the target keeps them.
+
+use core::fmt;
+use irq::isr::InterruptStack;
+use memory::paging::Mapper;
+use spin::Mutex;
+use vga::try_print;
+
+const WATCHPOINTS: usize = 4;
+/// Most frames a backtrace shows.
+const MAX_FRAMES: usize = 16;
+
+// DR6 bits.
+/// B0-B3, which watchpoints were hit.
+const DR6_HIT_MASK: u64 = 0b1111;
+/// The trap flag caused it.
+pub const DR6_SINGLE_STEP: u64 = 1 << 14;
+/// No conditions, reserved bits at their defaults.
+const DR6_RESET: u64 = 0xffff_0ff0;
+
+// DR7 bits.
+/// Exact breakpoints, recommended whenever any are enabled.
+const DR7_GLOBAL_EXACT: u64 = 1 << 9;
+
+/// RFLAGS.RF, stops an instruction breakpoint firing again on return.
+const RESUME_FLAG: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Execute,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    /// The R/W field of DR7.
+    fn bits(&self) -> u64
+    {
+        match *self {
+            WatchKind::Execute => 0b00,
+            WatchKind::Write => 0b01,
+            WatchKind::ReadWrite => 0b11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    /// All four debug registers are in use.
+    NoFreeSlot,
+    /// Length isn't 1, 2, 4 or 8, or is set for an execute watchpoint.
+    BadLength,
+    /// The address isn't aligned to the length.
+    Misaligned,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Watchpoint {
+    name: &'static str,
+    address: usize,
+    length: usize,
+    kind: WatchKind,
+}
+
+static ACTIVE: Mutex<[Option<Watchpoint>; WATCHPOINTS]> = Mutex::new([None; WATCHPOINTS]);
+
+/// Hit an `int3` here.
+pub fn breakpoint()
+{
+    unsafe { asm!("int3" :::: "volatile") };
+}
+
+/// Watch `length` bytes at `address`. Returns the slot for
+/// `clear_watchpoint`. Execute watchpoints must have a length of 1.
+pub fn set_watchpoint(name: &'static str, address: usize, length: usize, kind: WatchKind)
+                      -> Result<usize, WatchError>
+{
+    let length_bits = match (kind, length) {
+        (WatchKind::Execute, 1) => 0b00,
+        (WatchKind::Execute, _) => return Err(WatchError::BadLength),
+        (_, 1) => 0b00,
+        (_, 2) => 0b01,
+        (_, 4) => 0b11,
+        (_, 8) => 0b10,
+        _ => return Err(WatchError::BadLength),
+    };
+    if address % length != 0 {
+        return Err(WatchError::Misaligned);
+    }
+
+    let mut active = ACTIVE.lock();
+    let slot = try!(active.iter().position(|w| w.is_none()).ok_or(WatchError::NoFreeSlot));
+    active[slot] = Some(Watchpoint {
+        name: name,
+        address: address,
+        length: length,
+        kind: kind,
+    });
+
+    unsafe {
+        write_address(slot, address as u64);
+        let shift = 16 + slot * 4;
+        let mut dr7 = read_dr7() & !(0b1111 << shift);
+        dr7 |= (kind.bits() | length_bits << 2) << shift;
+        // Global enable for the slot.
+        dr7 |= 1 << (slot * 2 + 1);
+        write_dr7(dr7 | DR7_GLOBAL_EXACT);
+    }
+    Ok(slot)
+}
+
+pub fn clear_watchpoint(slot: usize)
+{
+    let mut active = ACTIVE.lock();
+    if slot >= WATCHPOINTS || active[slot].is_none() {
+        return;
+    }
+    active[slot] = None;
+    unsafe {
+        // The enables, and the R/W and LEN fields.
+        let dr7 = read_dr7() & !(0b11 << (slot * 2)) & !(0b1111 << (16 + slot * 4));
+        write_dr7(dr7);
+        write_address(slot, 0);
+    }
+}
+
+/// A watchpoint hit, displays as a line for the log.
+struct Hit {
+    watchpoint: Watchpoint,
+    rip: u64,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let w = &self.watchpoint;
+        // Data watchpoints trap after the access, instruction ones before.
+        let when = if w.kind == WatchKind::Execute { "at" } else { "after" };
+        write!(f, "Watchpoint {} ({:?}, {:#x}+{}) hit {} RIP {:#x}",
+               w.name, w.kind, w.address, w.length, when, self.rip)
+    }
+}
+
+/// Called from the #DB handler with DR6. Logs any watchpoints that
+/// were hit, returns false if none were. The log is dropped if the
+/// screen was locked, a watchpoint can go off in the middle of a print.
+pub fn watchpoint_hit(dr6: u64, stack: &mut InterruptStack) -> bool
+{
+    if dr6 & DR6_HIT_MASK == 0 {
+        return false;
+    }
+    // Don't deadlock if we stopped someone setting a watchpoint.
+    let active = match ACTIVE.try_lock() {
+        Some(active) => *active,
+        None => [None; WATCHPOINTS],
+    };
+    for slot in (0..WATCHPOINTS).filter(|&slot| dr6 & (1 << slot) != 0) {
+        match active[slot] {
+            Some(watchpoint) => {
+                try_print(format_args!("{}\n", Hit { watchpoint: watchpoint,
+                                                      rip: stack.frame.rip }));
+                if watchpoint.kind == WatchKind::Execute {
+                    stack.frame.rflags |= RESUME_FLAG;
+                }
+            }
+            None => try_print(format_args!("Watchpoint {} hit at RIP {:#x}\n",
+                                           slot, stack.frame.rip)),
+        }
+    }
+    true
+}
+
+/// Called from the #BP handler. Logs the site, unless the screen is
+/// locked, and carries on.
+pub fn breakpoint_hit(stack: &InterruptStack)
+{
+    // RIP is after the int3.
+    try_print(format_args!("Breakpoint at {:#x}\n{}", stack.frame.rip - 1, stack));
+}
+
+/// Return addresses up the stack from a frame pointer.
+pub struct Backtrace {
+    rbp: u64,
+}
+
+impl Backtrace {
+    pub fn new(rbp: u64) -> Backtrace
+    {
+        Backtrace { rbp: rbp }
+    }
+}
+
+/// Is it safe to read the u64 at `address`?
+fn readable(mapper: &Mapper, address: u64) -> bool
+{
+    let canonical = address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000;
+    canonical && address % 8 == 0 && mapper.translate(address as usize).is_some()
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        try!(writeln!(f, "Backtrace:"));
+        // Whatever's running, it's on the current address space.
+        let mapper = unsafe { Mapper::new() };
+        let mut rbp = self.rbp;
+        for depth in 0..MAX_FRAMES {
+            // [rbp] is the caller's rbp, [rbp + 8] the return address.
+            if !readable(&mapper, rbp) || !readable(&mapper, rbp + 8) {
+                break;
+            }
+            let (next, return_address) = unsafe {
+                (*(rbp as *const u64), *((rbp + 8) as *const u64))
+            };
+            if return_address == 0 {
+                break;
+            }
+            try!(writeln!(f, "  #{:2} {:#018x}", depth, return_address));
+            rbp = next;
+        }
+        Ok(())
+    }
+}
+
+pub fn read_dr6() -> u64
+{
+    let value: u64;
+    unsafe { asm!("mov %dr6, $0" : "=r"(value) ::: "volatile") };
+    value
+}
+
+/// The CPU never clears DR6, it has to be done after each #DB. It goes
+/// back to its reset value, the reserved bits have to keep theirs.
+pub fn clear_dr6()
+{
+    unsafe { asm!("mov $0, %dr6" :: "r"(DR6_RESET) :: "volatile") };
+}
+
+unsafe fn read_dr7() -> u64
+{
+    let value: u64;
+    asm!("mov %dr7, $0" : "=r"(value) ::: "volatile");
+    value
+}
+
+unsafe fn write_dr7(value: u64)
+{
+    asm!("mov $0, %dr7" :: "r"(value) :: "volatile");
+}
+
+unsafe fn write_address(slot: usize, address: u64)
+{
+    match slot {
+        0 => asm!("mov $0, %dr0" :: "r"(address) :: "volatile"),
+        1 => asm!("mov $0, %dr1" :: "r"(address) :: "volatile"),
+        2 => asm!("mov $0, %dr2" :: "r"(address) :: "volatile"),
+        3 => asm!("mov $0, %dr3" :: "r"(address) :: "volatile"),
+        _ => unreachable!(),
+    }
+}
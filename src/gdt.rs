@@ -1,14 +1,211 @@
 
+//! Global Descriptor Table and Task State Segment.
+//!
+//! The boot code loads a minimal GDT to get into long mode. This one
+//! replaces it, it has the same code and data selectors and adds a TSS
+//! so the interrupt stack table can be used.
+
+use core::mem;
+
+/// Interrupt stack table slots, these are what goes in the IDT. The
+/// TSS array is indexed from 0, the IDT from 1 (0 is no IST).
+pub const DOUBLE_FAULT_IST_INDEX: u8 = 1;
+pub const NMI_IST_INDEX: u8 = 2;
+pub const MACHINE_CHECK_IST_INDEX: u8 = 3;
+
+/// Size of each interrupt stack.
+const IST_STACK_SIZE: usize = 4096 * 4;
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const TSS_SELECTOR: u16 = 0x18;
+
+/// `size_of::<TaskStateSegment>()`, which isn't usable in a const fn.
+const TSS_SIZE: u16 = 104;
+
 #[repr(C, packed)]
-struct Gdt {
-    /// bits 0:15 of limit
-    limit0: u16,
-    /// bits 0:15 of base
-    base0: u16,
-    /// bits 16:23 of base
-    base1: u8,
-    /// access bits
-    access: u8,
-    /// 
-    flags_limits: u8
+pub struct TaskStateSegment {
+    reserved_1: u32,
+    /// Stacks loaded when changing to ring 0-2.
+    pub privilege_stack_table: [u64; 3],
+    reserved_2: u64,
+    /// Stacks for IDT entries with an IST index.
+    pub interrupt_stack_table: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn new() -> TaskStateSegment
+    {
+        TaskStateSegment {
+            reserved_1: 0,
+            privilege_stack_table: [0; 3],
+            reserved_2: 0,
+            interrupt_stack_table: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            // No IO permission bitmap.
+            iomap_base: TSS_SIZE,
+        }
+    }
+}
+
+// Descriptor bits.
+/// Set up front, or loading a segment writes it to the read-only GDT.
+const ACCESSED: u64     = 1 << 40;
+const WRITABLE: u64     = 1 << 41;
+const EXECUTABLE: u64   = 1 << 43;
+/// Code or data segment, clear for system segments (TSS).
+const USER_SEGMENT: u64 = 1 << 44;
+const PRESENT: u64      = 1 << 47;
+const LONG_MODE: u64    = 1 << 53;
+/// Type of an available 64-bit TSS.
+const TSS_AVAILABLE: u64 = 0x9 << 40;
+
+pub enum Descriptor {
+    UserSegment(u64),
+    /// System segments take two slots.
+    SystemSegment(u64, u64),
+}
+
+impl Descriptor {
+    pub fn kernel_code_segment() -> Descriptor
+    {
+        Descriptor::UserSegment(USER_SEGMENT | PRESENT | EXECUTABLE | LONG_MODE | ACCESSED)
+    }
+
+    pub fn kernel_data_segment() -> Descriptor
+    {
+        Descriptor::UserSegment(USER_SEGMENT | PRESENT | WRITABLE | ACCESSED)
+    }
+
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor
+    {
+        let base = tss as *const _ as u64;
+        let limit = (mem::size_of::<TaskStateSegment>() - 1) as u64;
+
+        let mut low = PRESENT | TSS_AVAILABLE;
+        low |= limit & 0xffff;
+        low |= ((limit >> 16) & 0xf) << 48;
+        low |= (base & 0xff_ffff) << 16;
+        low |= ((base >> 24) & 0xff) << 56;
+        let high = base >> 32;
+
+        Descriptor::SystemSegment(low, high)
+    }
+}
+
+#[repr(C, packed)]
+struct Gdtr {
+    limit: u16,
+    offset: u64,
+}
+
+pub struct Gdt {
+    table: [u64; 8],
+    next_free: usize,
+}
+
+impl Gdt {
+    pub const fn new() -> Gdt
+    {
+        // Entry 0 is the null descriptor.
+        Gdt { table: [0; 8], next_free: 1 }
+    }
+
+    /// Add a descriptor and return its selector.
+    pub fn add_entry(&mut self, entry: Descriptor) -> u16
+    {
+        let index = match entry {
+            Descriptor::UserSegment(value) => self.push(value),
+            Descriptor::SystemSegment(low, high) => {
+                let index = self.push(low);
+                self.push(high);
+                index
+            }
+        };
+        (index * 8) as u16
+    }
+
+    fn push(&mut self, value: u64) -> usize
+    {
+        assert!(self.next_free < self.table.len(), "GDT full");
+        let index = self.next_free;
+        self.table[index] = value;
+        self.next_free += 1;
+        index
+    }
+
+    /// Load the table. It has to live forever as the CPU keeps using it.
+    pub fn load(&'static self)
+    {
+        let gdtr = Gdtr {
+            limit: (self.next_free * 8 - 1) as u16,
+            offset: self.table.as_ptr() as u64,
+        };
+        unsafe {
+            asm!("lgdt ($0)" :: "r"(&gdtr) : "memory");
+        }
+    }
+}
+
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+ro_after_init! {
+    static mut GDT: Gdt = Gdt::new();
+}
+
+static mut DOUBLE_FAULT_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+static mut NMI_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+static mut MACHINE_CHECK_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+/// Top of a stack, 16 byte aligned.
+fn stack_top(stack: &'static [u8; IST_STACK_SIZE]) -> u64
+{
+    (stack.as_ptr() as u64 + IST_STACK_SIZE as u64) & !0xf
+}
+
+/// Build and load the GDT, then load the TSS.
+pub fn init()
+{
+    unsafe {
+        TSS.interrupt_stack_table[(DOUBLE_FAULT_IST_INDEX - 1) as usize] =
+            stack_top(&DOUBLE_FAULT_STACK);
+        TSS.interrupt_stack_table[(NMI_IST_INDEX - 1) as usize] =
+            stack_top(&NMI_STACK);
+        TSS.interrupt_stack_table[(MACHINE_CHECK_IST_INDEX - 1) as usize] =
+            stack_top(&MACHINE_CHECK_STACK);
+
+        let code = GDT.add_entry(Descriptor::kernel_code_segment());
+        let data = GDT.add_entry(Descriptor::kernel_data_segment());
+        let tss = GDT.add_entry(Descriptor::tss_segment(&TSS));
+        assert!(code == KERNEL_CODE_SELECTOR && data == KERNEL_DATA_SELECTOR &&
+                tss == TSS_SELECTOR, "GDT selectors moved");
+
+        GDT.load();
+        reload_segments(code, data);
+        load_tss(tss);
+    }
+}
+
+/// Reload CS with a far return and the data segments with moves.
+unsafe fn reload_segments(code: u16, data: u16)
+{
+    asm!("pushq $0
+          leaq 1f(%rip), %rax
+          pushq %rax
+          lretq
+          1:"
+         :: "r"(code as u64) : "rax", "memory" : "volatile");
+    asm!("mov $0, %ds
+          mov $0, %es
+          mov $0, %ss"
+         :: "r"(data) : "memory" : "volatile");
+}
+
+unsafe fn load_tss(selector: u16)
+{
+    asm!("ltr $0" :: "r"(selector) : "memory" : "volatile");
 }
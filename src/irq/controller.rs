@@ -0,0 +1,82 @@
+
+//! The interrupt controller IRQ lines come through.
+//!
+//! That's the 8259 PICs, or the APICs if the firmware describes them.
+//! It's picked once during boot by `initialize_interrupts`.
+
+use acpi::madt::{Polarity, TriggerMode};
+use super::apic::APIC;
+use super::pic::PICS;
+
+/// An I/O APIC input's line, and how the input is signalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GsiRoute {
+    pub line: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// What `dispatch` needs from an interrupt controller. Lines 0-15 are
+/// the ISA IRQs whichever controller is used, any others are I/O APIC
+/// inputs given a line by `route_gsi`.
+pub trait InterruptController {
+    fn name(&self) -> &'static str;
+
+    /// The IRQ line an interrupt vector comes from.
+    fn irq_line(&self, vector: u8) -> Option<u8>;
+
+    /// The IRQ line wired to I/O APIC global system interrupt `gsi`.
+    fn line_for_gsi(&self, gsi: u32) -> Option<u8>;
+
+    /// Give `gsi` a line if it hasn't got one, as an active high edge
+    /// triggered input. It's masked until the line has handlers. Inputs
+    /// that already had a line keep their signalling, which firmware
+    /// may have made level or active low. None if there's no such input
+    /// or no free line.
+    fn route_gsi(&mut self, gsi: u32) -> Option<GsiRoute>;
+
+    unsafe fn mask(&mut self, line: u8);
+
+    unsafe fn unmask(&mut self, line: u8);
+
+    /// Returns true, and counts it, if `vector` was a spurious
+    /// interrupt. Those must not be EOId.
+    unsafe fn is_spurious(&mut self, vector: u8) -> bool;
+
+    unsafe fn end_of_interrupt(&mut self, vector: u8);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerKind {
+    Pic,
+    Apic,
+}
+
+ro_after_init! {
+    static mut ACTIVE: ControllerKind = ControllerKind::Pic;
+}
+
+pub fn active() -> ControllerKind
+{
+    unsafe { ACTIVE }
+}
+
+/// Use `kind` from now on. Only during boot, and `APIC` has to be set
+/// before picking it.
+pub fn select(kind: ControllerKind)
+{
+    unsafe { ACTIVE = kind };
+}
+
+/// Run `f` with the active controller locked.
+pub fn with_controller<F, R>(f: F) -> R
+    where F: FnOnce(&mut InterruptController) -> R
+{
+    match active() {
+        ControllerKind::Pic => f(&mut *PICS.lock()),
+        ControllerKind::Apic => {
+            let mut apic = APIC.lock();
+            f(apic.as_mut().expect("APIC selected before it was set up"))
+        }
+    }
+}
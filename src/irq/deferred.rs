@@ -0,0 +1,109 @@
+
+//! Deferred interrupt work.
+//!
+//! IRQ handlers run with interrupts off, anything slow should be queued
+//! with `defer` instead. Queued work runs once the interrupt has been
+//! EOId with interrupts back on, or from the idle loop.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use cpu;
+use x86::irq::{enable, disable};
+
+const QUEUE_SIZE: usize = 64;
+
+/// Called with interrupts enabled and the argument given to `defer`.
+pub type WorkFn = fn(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+/// Ring buffer of work. There's only ever one producer, interrupts are
+/// off while pushing, and one consumer thanks to `RUNNING`, so it needs
+/// no lock.
+static mut QUEUE: [Option<(WorkFn, usize)>; QUEUE_SIZE] = [None; QUEUE_SIZE];
+/// Next item to run, only moved by the consumer.
+static HEAD: AtomicUsize = AtomicUsize::new(0);
+/// Next free slot, only moved by the producer.
+static TAIL: AtomicUsize = AtomicUsize::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Work thrown away because the queue was full.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Queue `work` to be called with `data` later. Safe to use from
+/// interrupt handlers.
+pub fn defer(work: WorkFn, data: usize) -> Result<(), QueueFull>
+{
+    // Keep it single producer when called outside an interrupt.
+    let enabled = cpu::interrupts_enabled();
+    unsafe { disable() };
+    let result = push(work, data);
+    if enabled {
+        unsafe { enable() };
+    }
+    result
+}
+
+fn push(work: WorkFn, data: usize) -> Result<(), QueueFull>
+{
+    let tail = TAIL.load(Ordering::Relaxed);
+    if tail.wrapping_sub(HEAD.load(Ordering::Acquire)) == QUEUE_SIZE {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return Err(QueueFull);
+    }
+    unsafe { QUEUE[tail % QUEUE_SIZE] = Some((work, data)) };
+    TAIL.store(tail.wrapping_add(1), Ordering::Release);
+    Ok(())
+}
+
+pub fn pending() -> bool
+{
+    HEAD.load(Ordering::Relaxed) != TAIL.load(Ordering::Acquire)
+}
+
+pub fn dropped() -> usize
+{
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Whether deferred work is running, somewhere up the stack.
+pub fn is_running() -> bool
+{
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Run everything queued, including anything queued while it runs.
+/// Does nothing if it's already running further up the stack.
+pub fn run_pending()
+{
+    // An IRQ that queues work just before `RUNNING` is cleared leaves
+    // it to us, so look again once it is.
+    while pending() {
+        if RUNNING.swap(true, Ordering::Acquire) {
+            return;
+        }
+        while pending() {
+            let head = HEAD.load(Ordering::Relaxed);
+            let work = unsafe { QUEUE[head % QUEUE_SIZE].take() };
+            // The slot can be reused as soon as it's been copied out.
+            HEAD.store(head.wrapping_add(1), Ordering::Release);
+            if let Some((work, data)) = work {
+                work(data);
+            }
+        }
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+/// Called at the end of an IRQ, after it's been EOId. Runs the queue
+/// with interrupts enabled if the interrupted code had them enabled.
+pub fn run_after_irq(interrupted_rflags: u64)
+{
+    if interrupted_rflags & cpu::INTERRUPT_FLAG == 0 ||
+        !pending() || RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+    unsafe { enable() };
+    run_pending();
+    // Back off for the return through the stub.
+    unsafe { disable() };
+}
@@ -0,0 +1,140 @@
+
+//! Hardware IRQ handlers.
+//!
+//! Drivers register a handler for an IRQ line, several can share a
+//! line. The line is unmasked while it has handlers, and is EOId after
+//! they've run, so drivers never touch the interrupt controller.
+
+use spin::IrqMutex;
+use super::apic::APIC;
+use super::controller::{active, with_controller, ControllerKind};
+use super::{deferred, nmi};
+use super::pic::PICS;
+use super::stats::{self, InterruptTable};
+
+/// Number of ISA IRQ lines, they're always lines 0-15.
+pub const ISA_LINES: usize = 16;
+/// Number of IRQ lines. The ones past the ISA lines are handed out by
+/// `InterruptController::route_gsi`.
+pub const IRQ_LINES: usize = 32;
+/// Most handlers that can share a line.
+const MAX_HANDLERS_PER_LINE: usize = 4;
+
+/// Called with interrupts disabled, keep it short. Slow work can be
+/// handed to `irq::defer`.
+pub type IrqHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// There's no such IRQ line.
+    InvalidLine,
+    /// The line already has `MAX_HANDLERS_PER_LINE` handlers.
+    LineFull,
+}
+
+static HANDLERS: IrqMutex<[[Option<IrqHandler>; MAX_HANDLERS_PER_LINE]; IRQ_LINES]> =
+    IrqMutex::new([[None; MAX_HANDLERS_PER_LINE]; IRQ_LINES]);
+
+fn same_handler(a: IrqHandler, b: IrqHandler) -> bool
+{
+    a as usize == b as usize
+}
+
+/// Call `handler` whenever `line` is raised. Unmasks the line.
+pub fn register(line: u8, handler: IrqHandler) -> Result<(), RegisterError>
+{
+    if line as usize >= IRQ_LINES {
+        return Err(RegisterError::InvalidLine);
+    }
+    let mut handlers = HANDLERS.lock();
+    {
+        let slot = try!(handlers[line as usize].iter_mut()
+                        .find(|h| h.is_none())
+                        .ok_or(RegisterError::LineFull));
+        *slot = Some(handler);
+    }
+    with_controller(|controller| unsafe { controller.unmask(line) });
+    Ok(())
+}
+
+/// Remove a handler added with `register`. The line is masked once it
+/// has no handlers left. Returns false if the handler wasn't registered.
+pub fn unregister(line: u8, handler: IrqHandler) -> bool
+{
+    if line as usize >= IRQ_LINES {
+        return false;
+    }
+    let mut handlers = HANDLERS.lock();
+    let line_handlers = &mut handlers[line as usize];
+    let found = match line_handlers.iter_mut()
+        .find(|h| h.map_or(false, |h| same_handler(h, handler))) {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    };
+    if line_handlers.iter().all(|h| h.is_none()) {
+        with_controller(|controller| unsafe { controller.mask(line) });
+    }
+    found
+}
+
+/// Whether anything is registered on `line`.
+pub fn has_handlers(line: u8) -> bool
+{
+    (line as usize) < IRQ_LINES &&
+        HANDLERS.lock()[line as usize].iter().any(|h| h.is_some())
+}
+
+/// Run the handlers for an IRQ vector and EOI it.
+pub fn dispatch(vector: u8)
+{
+    let (spurious, line) = with_controller(|controller| {
+        if unsafe { controller.is_spurious(vector) } {
+            (true, None)
+        } else {
+            (false, controller.irq_line(vector))
+        }
+    });
+    if spurious {
+        return stats::spurious(vector);
+    }
+    let line = match line {
+        Some(line) => line,
+        None => {
+            stats::unhandled(vector);
+            // The local APIC has every vector it delivers in service,
+            // IPIs included, and holds off lower priorities until it's
+            // EOId. The PICs only want their own lines EOId.
+            if active() == ControllerKind::Apic {
+                with_controller(|controller| unsafe { controller.end_of_interrupt(vector) });
+            }
+            return;
+        }
+    };
+
+    // Copied out so handlers can (un)register without deadlocking.
+    let line_handlers = HANDLERS.lock()[line as usize];
+    if line_handlers.iter().all(|h| h.is_none()) {
+        stats::unhandled(vector);
+    }
+    for handler in line_handlers.iter().filter_map(|h| *h) {
+        handler();
+    }
+
+    with_controller(|controller| unsafe { controller.end_of_interrupt(vector) });
+}
+
+/// Print interrupt statistics to the screen.
+pub fn print_statistics()
+{
+    print!("{}", InterruptTable);
+    let (master, slave) = PICS.lock().spurious_counts();
+    println!("Spurious IRQs: master PIC {}, slave PIC {}", master, slave);
+    if let Some(ref apic) = *APIC.lock() {
+        println!("Spurious APIC interrupts: {}", apic.spurious_count());
+    }
+    println!("Deferred work dropped: {}", deferred::dropped());
+    println!("External NMIs: {}", nmi::external_count());
+}
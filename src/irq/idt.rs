@@ -1,16 +1,200 @@
 
+//! The Interrupt Descriptor Table.
+//!
+//! Built at boot by `irq::initialize_interrupts` and read only after.
+
+use core::mem;
+
+/// Raw interrupt entry point. These are the stubs in `irq.asm`, they
+/// can't be normal Rust functions as they return with `iretq`.
+pub type HandlerFunc = unsafe extern "C" fn();
+
 #[repr(C, packed)]
 struct Idtr {
     limit: u16,
     offset: u64,
 }
 
-struct IdtEntry {
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct IdtEntry {
     offset0: u16,
     selector: u16,
-    _zero0: u8,
-    attr: u8,
+    options: EntryOptions,
     offset1: u16,
     offset2: u32,
     _zero1: u32
 }
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateType {
+    /// Interrupts are disabled while the handler runs.
+    Interrupt = 0xe,
+    /// Interrupts are left as they were.
+    Trap      = 0xf,
+}
+
+/// The type and attribute bits of an entry.
+///
+/// * 0:2   interrupt stack table index (0 is none)
+/// * 8:11  gate type
+/// * 13:14 descriptor privilege level
+/// * 15    present
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct EntryOptions(u16);
+
+impl EntryOptions {
+    /// Not present, any interrupt on it is a #NP.
+    const fn missing() -> EntryOptions
+    {
+        EntryOptions((GateType::Interrupt as u16) << 8)
+    }
+
+    /// Present ring 0 interrupt gate on the current stack.
+    const fn new() -> EntryOptions
+    {
+        EntryOptions(EntryOptions::missing().0 | 1 << 15)
+    }
+
+    pub fn set_present(&mut self, present: bool) -> &mut EntryOptions
+    {
+        self.0 = (self.0 & !(1 << 15)) | ((present as u16) << 15);
+        self
+    }
+
+    /// Lowest privilege level that can use `int` on this vector.
+    pub fn set_privilege_level(&mut self, dpl: u8) -> &mut EntryOptions
+    {
+        assert!(dpl <= 3, "invalid privilege level {}", dpl);
+        self.0 = (self.0 & !(0b11 << 13)) | ((dpl as u16) << 13);
+        self
+    }
+
+    pub fn set_gate_type(&mut self, gate_type: GateType) -> &mut EntryOptions
+    {
+        self.0 = (self.0 & !(0xf << 8)) | ((gate_type as u16) << 8);
+        self
+    }
+
+    /// Switch to interrupt stack `index` (1-7) from the TSS, or 0 to
+    /// stay on the current stack.
+    pub fn set_stack_index(&mut self, index: u8) -> &mut EntryOptions
+    {
+        assert!(index <= 7, "invalid interrupt stack index {}", index);
+        self.0 = (self.0 & !0b111) | index as u16;
+        self
+    }
+
+    pub fn present(&self) -> bool
+    {
+        self.0 & (1 << 15) != 0
+    }
+
+    pub fn privilege_level(&self) -> u8
+    {
+        ((self.0 >> 13) & 0b11) as u8
+    }
+
+    pub fn stack_index(&self) -> u8
+    {
+        (self.0 & 0b111) as u8
+    }
+}
+
+impl IdtEntry {
+    const fn missing() -> IdtEntry
+    {
+        IdtEntry {
+            offset0: 0,
+            selector: 0,
+            options: EntryOptions::missing(),
+            offset1: 0,
+            offset2: 0,
+            _zero1: 0,
+        }
+    }
+
+    fn new(selector: u16, handler: HandlerFunc) -> IdtEntry
+    {
+        let address = handler as u64;
+        IdtEntry {
+            offset0: address as u16,
+            selector: selector,
+            options: EntryOptions::new(),
+            offset1: (address >> 16) as u16,
+            offset2: (address >> 32) as u32,
+            _zero1: 0,
+        }
+    }
+}
+
+pub struct Idt([IdtEntry; 256]);
+
+impl Idt {
+    pub const fn new() -> Idt
+    {
+        Idt([IdtEntry::missing(); 256])
+    }
+
+    /// Point `vector` at `handler` with the default options, which
+    /// can be changed through the returned reference.
+    pub fn set_handler(&mut self, vector: u8, handler: HandlerFunc) -> &mut EntryOptions
+    {
+        self.0[vector as usize] = IdtEntry::new(code_selector(), handler);
+        &mut self.0[vector as usize].options
+    }
+
+    pub fn options(&mut self, vector: u8) -> &mut EntryOptions
+    {
+        &mut self.0[vector as usize].options
+    }
+
+    /// Load the table into the IDT register. It has to live forever as
+    /// the CPU keeps using it.
+    pub fn load(&'static self)
+    {
+        let idtr = Idtr {
+            limit: (mem::size_of::<Idt>() - 1) as u16,
+            offset: self as *const _ as u64,
+        };
+        unsafe {
+            asm!("lidt ($0)" :: "r"(&idtr) : "memory");
+        }
+    }
+}
+
+ro_after_init! {
+    static mut IDT: Idt = Idt::new();
+}
+
+/// Set the handler for `vector` in the kernel's IDT. Only usable during
+/// boot, the IDT is read only afterwards.
+pub fn set_handler(vector: u8, handler: HandlerFunc) -> &'static mut EntryOptions
+{
+    unsafe { IDT.set_handler(vector, handler) }
+}
+
+/// The gate options of `vector` in the kernel's IDT. Only usable
+/// during boot.
+pub fn options(vector: u8) -> &'static mut EntryOptions
+{
+    unsafe { IDT.options(vector) }
+}
+
+/// Load the kernel's IDT.
+pub fn load()
+{
+    unsafe { IDT.load() }
+}
+
+/// The current code segment, which is what handlers run in.
+fn code_selector() -> u16
+{
+    let selector: u16;
+    unsafe {
+        asm!("mov %cs, $0" : "=r"(selector));
+    }
+    selector
+}
@@ -1,47 +1,220 @@
 
-use memory::paging::VirtualAddress;
 use core::intrinsics::{volatile_store, volatile_load};
 use core::u64;
+use core::fmt;
+use memory::{mmiotrace, user_access};
+use cpu;
+use debug;
+use mce;
+use super::{deferred, dispatch, nmi, stats};
+use memory::paging::fault::PageFault;
+use x86::controlregs::{cr2, cr3};
 
 // 0 is a valid interrupt so MAX is used to signify a non
 static mut LAST_INTERRUPT: u64 = u64::MAX;
 
-static EXCEPTION_NAME: [&'static str; 21] =
-    [ "Divide Error",
-      "Debug Exeception",
-      "NMI Interrupt (not an exception)",
-      "Breakpoint",
-      "Overflow",
-      "BOUND Range exceeded",
-      "Invalid Opcode (Undefined Opcode)",
-      "Device not Available (No Math Coprocessor)",
-      "Double Fault",
-      "Coprocessor Segment overrun",
-      "Invalid TSS",
-      "Segment not present",
-      "Stack-Segment fault",
-      "General Protection fault",
-      "Page fault",
-      "__RESERVED__",
-      "x87 FPU Floating-Point Error (Math fault)",
-      "Alignment check",
-      "Machine Check",
-      "SIMD Floating-Point exception",
-      "Virtualisation Exception", ];
+ro_after_init! {
+    static EXCEPTION_NAME: [&'static str; 21] =
+        [ "Divide Error",
+          "Debug Exeception",
+          "NMI Interrupt (not an exception)",
+          "Breakpoint",
+          "Overflow",
+          "BOUND Range exceeded",
+          "Invalid Opcode (Undefined Opcode)",
+          "Device not Available (No Math Coprocessor)",
+          "Double Fault",
+          "Coprocessor Segment overrun",
+          "Invalid TSS",
+          "Segment not present",
+          "Stack-Segment fault",
+          "General Protection fault",
+          "Page fault",
+          "__RESERVED__",
+          "x87 FPU Floating-Point Error (Math fault)",
+          "Alignment check",
+          "Machine Check",
+          "SIMD Floating-Point exception",
+          "Virtualisation Exception", ];
+}
+
+/// General purpose registers in the order `irq.asm` pushes them. (rax
+/// is pushed last so it comes first)
+#[repr(C)]
+pub struct SavedRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rbp: u64,
+}
+
+/// What the CPU pushes when it takes an interrupt. Changes made here
+/// are picked up by `iretq`.
+#[repr(C)]
+pub struct InterruptFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Everything on the stack when `interrupt_handler` is called. The
+/// stubs in `irq.asm` push a dummy error code when the CPU doesn't, so
+/// this is the same for every vector.
+#[repr(C)]
+pub struct InterruptStack {
+    pub registers: SavedRegisters,
+    pub vector: u64,
+    pub error_code: u64,
+    pub frame: InterruptFrame,
+}
+
+impl mmiotrace::Trapped for InterruptStack {
+    fn rip(&self) -> u64
+    {
+        self.frame.rip
+    }
+
+    fn rflags(&self) -> u64
+    {
+        self.frame.rflags
+    }
+
+    fn set_rflags(&mut self, rflags: u64)
+    {
+        self.frame.rflags = rflags;
+    }
+
+    fn register(&self, index: u8) -> u64
+    {
+        let r = &self.registers;
+        match index {
+            0 => r.rax,
+            1 => r.rcx,
+            2 => r.rdx,
+            3 => r.rbx,
+            4 => self.frame.rsp,
+            5 => r.rbp,
+            6 => r.rsi,
+            7 => r.rdi,
+            8 => r.r8,
+            9 => r.r9,
+            10 => r.r10,
+            11 => r.r11,
+            12 => r.r12,
+            13 => r.r13,
+            14 => r.r14,
+            15 => r.r15,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Display for InterruptStack {
+    /// Everything needed to debug an exception, it fits on the VGA
+    /// screen along with the panic message.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let r = &self.registers;
+        let (cr2, cr3) = unsafe { (cr2(), cr3()) };
+        try!(writeln!(f, "RIP={:016x} CS={:04x} RFLAGS={:016x}",
+                      self.frame.rip, self.frame.cs, self.frame.rflags));
+        try!(writeln!(f, "RSP={:016x} SS={:04x} ERROR={:016x}",
+                      self.frame.rsp, self.frame.ss, self.error_code));
+        try!(writeln!(f, "CR2={:016x} CR3={:016x}", cr2, cr3));
+        try!(writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x}", r.rax, r.rbx, r.rcx));
+        try!(writeln!(f, "RDX={:016x} RSI={:016x} RDI={:016x}", r.rdx, r.rsi, r.rdi));
+        try!(writeln!(f, "RBP={:016x} R8 ={:016x} R9 ={:016x}", r.rbp, r.r8, r.r9));
+        try!(writeln!(f, "R10={:016x} R11={:016x} R12={:016x}", r.r10, r.r11, r.r12));
+        writeln!(f, "R13={:016x} R14={:016x} R15={:016x}", r.r13, r.r14, r.r15)
+    }
+}
 
 #[no_mangle]
-pub extern fn interrupt_handler(number: u64, _stack_address: VirtualAddress)
+pub extern fn interrupt_handler(stack: &mut InterruptStack)
 {
     unsafe {
-        volatile_store(&mut LAST_INTERRUPT, number);
+        volatile_store(&mut LAST_INTERRUPT, stack.vector);
+    }
+
+    let start = cpu::rdtsc();
+    match stack.vector {
+        1 => debug(stack),
+        2 => nmi::handle(stack),
+        3 => debug::breakpoint_hit(stack),
+        14 => page_fault(stack),
+        18 => mce::machine_check(stack),
+        0...31 => exception(stack),
+        vector => dispatch::dispatch(vector as u8),
+    }
+    stats::record(stack.vector as u8, cpu::rdtsc().wrapping_sub(start));
+
+    // Exceptions and NMIs can interrupt anything, only IRQs are safe
+    // places to run deferred work.
+    if stack.vector >= 32 {
+        deferred::run_after_irq(stack.frame.rflags);
+    }
+}
+
+/// Name of exception `vector`, None if it's not an exception.
+pub fn exception_name(vector: u8) -> Option<&'static str>
+{
+    match vector {
+        0...20 => Some(EXCEPTION_NAME[vector as usize]),
+        21...31 => Some("Intel reserved"),
+        _ => None,
+    }
+}
+
+/// An exception we can't recover from.
+fn exception(stack: &InterruptStack) -> !
+{
+    let number = stack.vector as usize;
+    match EXCEPTION_NAME.get(number) {
+        Some(name) => panic!("CPU Exception ({}): {}\n{}", number, name, stack),
+        None => panic!("Intel reserved interrupt: {}\n{}", number, stack),
+    }
+}
+
+/// #DB, from single stepping (mmiotrace) or a watchpoint.
+fn debug(stack: &mut InterruptStack)
+{
+    let dr6 = debug::read_dr6();
+    debug::clear_dr6();
+
+    let mut handled = false;
+    if dr6 & debug::DR6_SINGLE_STEP != 0 {
+        handled |= mmiotrace::single_step(stack);
+    }
+    handled |= debug::watchpoint_hit(dr6, stack);
+    if !handled {
+        exception(stack)
+    }
+}
+
+fn page_fault(stack: &mut InterruptStack)
+{
+    let address = unsafe { cr2() } as usize;
+    if mmiotrace::page_fault(address, stack) {
+        return;
     }
-    // Shouldn't return from these interrupts so panic instead.
-    match number {
-        0 | 1 | 3...21 => panic!("CPU Exception ({}): {}", number,
-                                 EXCEPTION_NAME[number as usize]),
-        22...31 => panic!("Intel reserved interrupt: {}", number),
-        _ => (),
+    if let Some(violation) = user_access::classify_fault(address, stack.error_code,
+                                                         stack.frame.rflags) {
+        panic!("{} at {:#x}\n{}", violation, address, stack);
     }
+    panic!("{}{}", PageFault::new(address, stack.error_code), stack);
 }
 
 pub fn get_last_interrupt() -> Option<u64>
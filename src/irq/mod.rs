@@ -1,17 +1,83 @@
 
 pub mod pic;
 pub mod isr;
+pub mod idt;
+pub mod dispatch;
+pub mod controller;
+pub mod apic;
+pub mod stats;
+pub mod deferred;
+pub mod nmi;
 
+pub use self::dispatch::{register, unregister, print_statistics};
+pub use self::dispatch::{IrqHandler, RegisterError};
+pub use self::controller::{InterruptController, ControllerKind, GsiRoute, with_controller};
+pub use self::deferred::{defer, QueueFull};
+
+use gdt;
+use memory::FrameAllocator;
+use memory::paging::Mapper;
+use self::controller::select;
+use self::idt::HandlerFunc;
 use self::pic::PICS;
 
-pub fn initialize_interrupts()
+extern {
+    /// Entry points for every vector, from `irq.asm`.
+    static isr_stub_table: [HandlerFunc; 256];
+}
+
+/// Build the IDT and set up the APICs, or the PICs if there aren't any.
+/// The mapper and allocator are for mapping the APICs.
+pub fn initialize_interrupts<A>(mapper: &mut Mapper, allocator: &mut A)
+    where A: FrameAllocator
 {
+    print!("Building IDT... ");
+    for vector in 0..256 {
+        idt::set_handler(vector as u8, unsafe { isr_stub_table[vector] });
+    }
+    // These must always get a working stack, even if the kernel stack
+    // overflowed.
+    idt::options(8).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    idt::options(2).set_stack_index(gdt::NMI_IST_INDEX);
+    idt::options(18).set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    idt::load();
+    println!("Done");
+
     print!("Initialising PICs... ");
-    let mut pics = PICS.lock();
     unsafe {
+        // Even if they're not used they have to be moved off the
+        // exception vectors.
+        let mut pics = PICS.lock();
         pics.initialize();
-        println!("Done");
+        // Lines are unmasked as handlers are registered.
+        for line in 0..16 {
+            if line != 2 {
+                pics.set_mask(line);
+            }
+        }
+    }
+    println!("Done");
+
+    print!("Initialising APIC... ");
+    match apic::init(mapper, allocator) {
+        Ok(()) => {
+            unsafe { PICS.lock().set_mask(2) };
+            select(ControllerKind::Apic);
+            println!("Done, using {}", with_controller(|c| c.name()));
+        }
+        Err(error) => println!("{:?}, using the PICs", error),
+    }
+    unsafe {
         ::x86::irq::enable();
         // We not in Kansas anymore.
     }
+
+    if controller::active() == ControllerKind::Apic {
+        print!("Checking self IPIs... ");
+        if apic::self_test() {
+            println!("OK");
+        } else {
+            println!("not received, the local APIC isn't taking interrupts");
+        }
+    }
 }
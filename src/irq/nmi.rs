@@ -0,0 +1,43 @@
+
+//! Non-maskable interrupts.
+//!
+//! They run on their own stack and can land anywhere, including in
+//! code holding a lock with interrupts off, so nothing here may wait
+//! for a lock.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use port::Port;
+use vga::try_print;
+use watchdog;
+use super::isr::InterruptStack;
+
+/// System control port B, says why the chipset raised an NMI.
+const NMI_STATUS_PORT: u16 = 0x61;
+const NMI_SERR: u8 = 1 << 7;
+const NMI_IOCHK: u8 = 1 << 6;
+
+/// NMIs that weren't the watchdog's.
+static EXTERNAL: AtomicUsize = AtomicUsize::new(0);
+
+pub fn handle(stack: &mut InterruptStack)
+{
+    if watchdog::nmi(stack) {
+        return;
+    }
+
+    EXTERNAL.fetch_add(1, Ordering::Relaxed);
+    let status = unsafe { Port::<u8>::new(NMI_STATUS_PORT) }.read();
+    let reason = if status & NMI_SERR != 0 {
+        "PCI system error"
+    } else if status & NMI_IOCHK != 0 {
+        "I/O channel check"
+    } else {
+        "unknown source"
+    };
+    try_print(format_args!("NMI: {} (port 0x61 = {:#04x})\n{}", reason, status, stack));
+}
+
+pub fn external_count() -> usize
+{
+    EXTERNAL.load(Ordering::Relaxed)
+}
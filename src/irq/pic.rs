@@ -1,11 +1,14 @@
 
-use spin::Mutex;
+use spin::IrqMutex;
 use port::{Port, UnsafePort};
+use super::controller::{InterruptController, GsiRoute};
 
 pub struct Pic {
     offset: u8,
     command: UnsafePort<u8>,
     data: UnsafePort<u8>,
+    /// Spurious interrupts raised by this PIC.
+    spurious: usize,
 }
 
 #[repr(u8)]
@@ -62,7 +65,7 @@ pub struct ChainedPics {
 ///
 /// This is not initialized until .initalise is called.
 /// (Obviously can't do that statically)
-pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(0x20, 0x28) });
+pub static PICS: IrqMutex<ChainedPics> = IrqMutex::new(unsafe { ChainedPics::new(0x20, 0x28) });
 
 impl ChainedPics {
     const unsafe fn new(offset1: u8,
@@ -76,11 +79,13 @@ impl ChainedPics {
                     offset: offset1,
                     command: UnsafePort::new(0x20),
                     data: UnsafePort::new(0x21),
+                    spurious: 0,
                 },
                 Pic {
                     offset: offset2,
                     command: UnsafePort::new(0xA0),
                     data: UnsafePort::new(0xA1),
+                    spurious: 0,
                 }]
         }
     }
@@ -135,6 +140,18 @@ impl ChainedPics {
         self.pics.iter().any(|pic| pic.handles_interrupt(interrupt_id))
     }
 
+    /// The IRQ line (0-15) an interrupt vector comes from.
+    pub fn irq_line(&self, interrupt_id: u8) -> Option<u8>
+    {
+        if self.pics[0].handles_interrupt(interrupt_id) {
+            Some(interrupt_id - self.pics[0].offset)
+        } else if self.pics[1].handles_interrupt(interrupt_id) {
+            Some(interrupt_id - self.pics[1].offset + 8)
+        } else {
+            None
+        }
+    }
+
     pub unsafe fn end_of_interrupt(&mut self, interrupt_id: u8)
     {
         self.check();
@@ -146,6 +163,38 @@ impl ChainedPics {
         self.pics[0].end_of_interrupt();
     }
 
+    /// A PIC raises its lowest priority line (IRQ 7 or 15) when an
+    /// interrupt goes away before it's acknowledged. These must not be
+    /// EOId, except that a spurious IRQ 15 still needs an EOI to the
+    /// master as it did see IRQ 2. Returns true, and counts it, if the
+    /// interrupt was spurious.
+    pub unsafe fn is_spurious(&mut self, interrupt_id: u8) -> bool
+    {
+        self.check();
+        let line = match self.irq_line(interrupt_id) {
+            Some(line) if line == 7 || line == 15 => line,
+            _ => return false,
+        };
+
+        let (master_isr, slave_isr) = self.read_isr();
+        if line == 7 && master_isr & (1 << 7) == 0 {
+            self.pics[0].spurious += 1;
+            true
+        } else if line == 15 && slave_isr & (1 << 7) == 0 {
+            self.pics[1].spurious += 1;
+            self.pics[0].end_of_interrupt();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Spurious interrupts from the (master, slave) PIC.
+    pub fn spurious_counts(&self) -> (usize, usize)
+    {
+        (self.pics[0].spurious, self.pics[1].spurious)
+    }
+
     unsafe fn select_irq(&mut self, irq_line: u8) -> (&mut UnsafePort<u8>, u8)
     {
         if irq_line < 8 {
@@ -194,6 +243,54 @@ impl ChainedPics {
     }
 }
 
+impl InterruptController for ChainedPics {
+    fn name(&self) -> &'static str
+    {
+        "8259 PIC"
+    }
+
+    fn irq_line(&self, vector: u8) -> Option<u8>
+    {
+        ChainedPics::irq_line(self, vector)
+    }
+
+    fn line_for_gsi(&self, _gsi: u32) -> Option<u8>
+    {
+        // No I/O APIC.
+        None
+    }
+
+    fn route_gsi(&mut self, _gsi: u32) -> Option<GsiRoute>
+    {
+        None
+    }
+
+    unsafe fn mask(&mut self, line: u8)
+    {
+        // Only the ISA lines are wired to the PICs.
+        if line < 16 {
+            self.set_mask(line)
+        }
+    }
+
+    unsafe fn unmask(&mut self, line: u8)
+    {
+        if line < 16 {
+            self.clear_mask(line)
+        }
+    }
+
+    unsafe fn is_spurious(&mut self, vector: u8) -> bool
+    {
+        ChainedPics::is_spurious(self, vector)
+    }
+
+    unsafe fn end_of_interrupt(&mut self, vector: u8)
+    {
+        ChainedPics::end_of_interrupt(self, vector)
+    }
+}
+
 /// Do a short wait. Long enough for configuring PICs.
 /// Works by writing to an unused port.
 fn io_wait()
@@ -0,0 +1,87 @@
+
+//! Per-vector interrupt statistics.
+//!
+//! Counters are bumped from interrupt context and read from anywhere,
+//! so they're updated atomically. `InterruptTable` formats them like
+//! Linux's /proc/interrupts.
+
+use core::fmt;
+use core::intrinsics::{atomic_load, atomic_umax, atomic_xadd};
+use super::controller::with_controller;
+use super::isr;
+
+const VECTORS: usize = 256;
+
+static mut COUNTS: [usize; VECTORS] = [0; VECTORS];
+static mut SPURIOUS: [usize; VECTORS] = [0; VECTORS];
+static mut UNHANDLED: [usize; VECTORS] = [0; VECTORS];
+/// Longest time spent handling each vector, in TSC cycles.
+static mut MAX_CYCLES: [u64; VECTORS] = [0; VECTORS];
+
+/// Count an interrupt that took `cycles` to handle.
+pub fn record(vector: u8, cycles: u64)
+{
+    unsafe {
+        atomic_xadd(&mut COUNTS[vector as usize], 1);
+        atomic_umax(&mut MAX_CYCLES[vector as usize], cycles);
+    }
+}
+
+/// Count a spurious interrupt on `vector`.
+pub fn spurious(vector: u8)
+{
+    unsafe { atomic_xadd(&mut SPURIOUS[vector as usize], 1) };
+}
+
+/// Count an interrupt nothing was registered to handle.
+pub fn unhandled(vector: u8)
+{
+    unsafe { atomic_xadd(&mut UNHANDLED[vector as usize], 1) };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VectorStats {
+    pub count: usize,
+    pub spurious: usize,
+    pub unhandled: usize,
+    pub max_cycles: u64,
+}
+
+pub fn vector_stats(vector: u8) -> VectorStats
+{
+    let i = vector as usize;
+    unsafe {
+        VectorStats {
+            count: atomic_load(&COUNTS[i]),
+            spurious: atomic_load(&SPURIOUS[i]),
+            unhandled: atomic_load(&UNHANDLED[i]),
+            max_cycles: atomic_load(&MAX_CYCLES[i]),
+        }
+    }
+}
+
+/// Every vector that's been raised, one per line.
+pub struct InterruptTable;
+
+impl fmt::Display for InterruptTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let controller = with_controller(|c| c.name());
+        try!(writeln!(f, "vector      count spurious unhandled max cycles"));
+        for vector in 0..VECTORS {
+            let vector = vector as u8;
+            let stats = vector_stats(vector);
+            if stats.count == 0 {
+                continue;
+            }
+            try!(write!(f, "  {:#04x} {:10} {:8} {:9} {:10}  ", vector, stats.count,
+                        stats.spurious, stats.unhandled, stats.max_cycles));
+            match (isr::exception_name(vector), with_controller(|c| c.irq_line(vector))) {
+                (Some(name), _) => try!(writeln!(f, "{}", name)),
+                (None, Some(line)) => try!(writeln!(f, "IRQ {} ({})", line, controller)),
+                (None, None) => try!(writeln!(f, "")),
+            }
+        }
+        Ok(())
+    }
+}
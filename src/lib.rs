@@ -6,13 +6,17 @@
 #![feature(step_by)]
 #![feature(asm)]
 #![feature(core_intrinsics)]
-#![no_std]
+// Unit tests run on the host, with std.
+#![cfg_attr(not(test), no_std)]
 
 // Lots of dead code until we acutally start using it.
 // So this temporily here so I can see the useful warning.
 #![allow(dead_code)]
 
+#[cfg(not(test))]
 extern crate rlibc;
+#[cfg(test)]
+extern crate core;
 // extern crate spin;
 extern crate multiboot2;
 #[macro_use]
@@ -21,16 +25,25 @@ extern crate x86;
 
 #[macro_use]
 pub mod vga;
+#[macro_use]
 pub mod memory;
 pub mod port;
 pub mod irq;
 pub mod token;
 pub mod spin;
+pub mod cpu;
+pub mod gdt;
+pub mod acpi;
+pub mod debug;
+pub mod watchdog;
+pub mod mce;
+pub mod time;
 
 // CAUTION: We have a small stack and no guard page.  Go too far
 // and we rewrite the page table.  I guess that will cause a PageFault
 // anyway.
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern fn rust_main(multiboot_information_address: usize,
                         dispenser: token::Dispenser)
@@ -47,6 +60,12 @@ pub extern fn rust_main(multiboot_information_address: usize,
 
     println!("Enabling WP bit");
     enable_write_protect_bit();
+
+    print!("Enabling SMEP/SMAP... ");
+    memory::user_access::enable_protection();
+    println!("SMEP: {}, SMAP: {}",
+             memory::user_access::smep_enabled(),
+             memory::user_access::smap_enabled());
     
     let boot_info = unsafe { multiboot2::load(multiboot_information_address) };
     let memory_map_tag = boot_info.memory_map_tag()
@@ -66,12 +85,21 @@ pub extern fn rust_main(multiboot_information_address: usize,
                  section.addr, section.size, section.flags);
     }
 
-    let kernel_start = elf_sections_tag.sections().map(|s| s.addr)
+    // The frame allocator wants physical addresses.
+    let kernel_sections = || {
+        use multiboot2::ELF_SECTION_ALLOCATED;
+        elf_sections_tag.sections()
+            .filter(|s| s.flags().contains(ELF_SECTION_ALLOCATED))
+    };
+    let kernel_start = kernel_sections()
+        .map(|s| memory::kernel_to_physical(s.addr as usize))
         .min().unwrap();
-    let kernel_end = elf_sections_tag.sections().map(|s| s.addr + s.size)
+    let kernel_end = kernel_sections()
+        .map(|s| memory::kernel_to_physical((s.addr + s.size) as usize))
         .max().unwrap();
 
-    let multiboot_start = multiboot_information_address;
+    // The boot code moves the multiboot address into the higher half.
+    let multiboot_start = memory::kernel_to_physical(multiboot_information_address);
     let multiboot_end = multiboot_start + (boot_info.total_size as usize);
 
     println!("kernel_start:    {:#8x}, end: {:#8x}", kernel_start, kernel_end);
@@ -80,14 +108,15 @@ pub extern fn rust_main(multiboot_information_address: usize,
     let frame_token = dispenser.frame_token().expect("Frame token missing");
     let mut frame_allocator =
         memory::AreaFrameAllocator::new(frame_token,
-                                        kernel_start as usize, kernel_end as usize,
+                                        kernel_start, kernel_end,
                                         multiboot_start, multiboot_end,
                                         memory_map_tag.memory_areas());
 
     // this is the new part
-    let page_table = memory::paging::remap_the_kernel(&mut frame_allocator,
+    let mut page_table = memory::paging::remap_the_kernel(&mut frame_allocator,
                                                       (multiboot_start, multiboot_end),
-                                                      boot_info);
+                                                      boot_info)
+        .expect("Failed to remap the kernel");
     println!("It did not crash!");
 
     let (alloc, dealloc) = frame_allocator.get_alloc_counts();
@@ -101,9 +130,83 @@ pub extern fn rust_main(multiboot_information_address: usize,
         println!("All good");
     }
 
+    println!("Loading GDT and TSS");
+    gdt::init();
+
+    print!("Looking for ACPI... ");
+    match acpi::init(&mut page_table, &mut frame_allocator) {
+        Ok(()) => println!("Found"),
+        Err(error) => println!("{:?}", error),
+    }
+
     println!("Initialising interrupts");
-    irq::initialize_interrupts();
-    halt();
+    irq::initialize_interrupts(&mut page_table, &mut frame_allocator);
+
+    // Tracing takes page faults, so it can't start before the IDT.
+    // Regions mapped before here (ACPI, the APICs) aren't traced.
+    if cfg!(feature = "mmiotrace") {
+        println!("Tracing MMIO");
+        memory::mmiotrace::enable();
+    }
+
+    // Before the PIT starts counting ticks, they'd be lost while it runs.
+    print!("Calibrating the TSC... ");
+    println!("{} Hz", time::tsc::calibrate());
+
+    print!("Starting PIT... ");
+    time::pit::init(time::pit::DEFAULT_FREQUENCY);
+    println!("{} mHz", time::pit::frequency_millihertz());
+
+    print!("Looking for an HPET... ");
+    match time::hpet::init(&mut page_table, &mut frame_allocator) {
+        Ok(()) => println!("{} Hz, {} timers", time::hpet::frequency(), time::hpet::timer_count()),
+        Err(error) => println!("{:?}", error),
+    }
+    // Timers interrupt through the I/O APIC.
+    if cfg!(feature = "hpettest") && time::hpet::is_present() &&
+        irq::controller::active() == irq::ControllerKind::Apic {
+        print!("Testing HPET timer 0... ");
+        match time::hpet::test_timer() {
+            Ok(line) => println!("OK on IRQ {}", line),
+            Err(error) => println!("{:?}", error),
+        }
+    }
+
+    print!("Choosing a clock... ");
+    let clock = time::clock::init();
+    println!("{} at {} Hz", clock.name(), clock.frequency());
+
+    print!("Reading the RTC... ");
+    time::rtc::init();
+    println!("{}", time::rtc::wall_clock_time());
+
+    print!("Enabling machine checks... ");
+    println!("{} banks", mce::init());
+    mce::start_polling();
+
+    print!("Starting lockup watchdog... ");
+    match watchdog::init() {
+        Ok(()) => println!("Done"),
+        Err(error) => println!("{:?}", error),
+    }
+
+    // Boot is done, nothing should change the boot configuration now.
+    memory::ro_after_init::protect(&mut page_table)
+        .expect("Failed to protect ro_after_init data");
+    if cfg!(feature = "mmiotrace") {
+        memory::mmiotrace::print_trace();
+    }
+
+    idle();
+}
+
+/// Run deferred interrupt work, then wait for more.
+fn idle() -> !
+{
+    loop {
+        irq::deferred::run_pending();
+        cpu::halt_unless(irq::deferred::pending);
+    }
 }
 
 /// Halt the processor with the hlt instruction.
@@ -132,11 +235,13 @@ fn enable_write_protect_bit()
     unsafe { cr0_write(cr0() | wp_bit) };
 }
 
+#[cfg(not(test))]
 #[lang = "eh_personality"]
 extern fn eh_personality()
 {
 }
 
+#[cfg(not(test))]
 #[lang = "panic_fmt"]
 extern fn panic_fmt(fmt: core::fmt::Arguments,
                     file: &str,
@@ -145,7 +250,9 @@ extern fn panic_fmt(fmt: core::fmt::Arguments,
     use vga::*;
     use core::fmt::Write;
 
-    let mut lock = vga::WRITER.lock();
+    // Whoever holds the screen isn't getting it back, it may even be
+    // the code that panicked.
+    let mut lock = unsafe { vga::WRITER.force_lock() };
     
     lock.set_color(ColorCode::new(Color::LightRed, Color::Black));
     lock.write_bytes(&[b'*'; 80][..]);
@@ -0,0 +1,315 @@
+
+//! Machine check architecture.
+//!
+//! Hardware errors are logged in banks of MSRs. Uncorrected ones raise
+//! a #MC, corrected ones are only logged and are found by `poll`, which
+//! runs from a timer every `POLL_INTERVAL`.
+
+use core::{cmp, fmt};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cpu;
+use irq::isr::InterruptStack;
+use time::{clock, timer, NANOS_PER_SECOND};
+use vga::try_print;
+use x86::controlregs::{cr4, cr4_write};
+use x86::msr::{rdmsr, wrmsr};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MCG_CTL: u32 = 0x17b;
+/// Each bank is CTL, STATUS, ADDR and MISC from here.
+const IA32_MC0_CTL: u32 = 0x400;
+
+// MCG_CAP bits.
+const MCG_BANK_COUNT: u64 = 0xff;
+const MCG_CTL_PRESENT: u64 = 1 << 8;
+
+// MCG_STATUS bits.
+/// The interrupted RIP can be returned to.
+const MCG_RIPV: u64 = 1 << 0;
+/// The interrupted RIP is where the error happened.
+const MCG_EIPV: u64 = 1 << 1;
+const MCG_MCIP: u64 = 1 << 2;
+
+// MCi_STATUS bits.
+const STATUS_VALID: u64 = 1 << 63;
+const STATUS_OVERFLOW: u64 = 1 << 62;
+const STATUS_UNCORRECTED: u64 = 1 << 61;
+const STATUS_ENABLED: u64 = 1 << 60;
+const STATUS_MISC_VALID: u64 = 1 << 59;
+const STATUS_ADDR_VALID: u64 = 1 << 58;
+/// Processor context corrupt, there's no recovering.
+const STATUS_PCC: u64 = 1 << 57;
+
+const CR4_MCE: usize = 1 << 6;
+
+/// Most banks we look at, MCG_CAP allows up to 255.
+const MAX_BANKS: usize = 32;
+/// Nanoseconds between looking for corrected errors.
+const POLL_INTERVAL: u64 = 5 * NANOS_PER_SECOND;
+
+static mut BANKS: usize = 0;
+static CORRECTED: AtomicUsize = AtomicUsize::new(0);
+
+fn ctl_msr(bank: usize) -> u32 { IA32_MC0_CTL + 4 * bank as u32 }
+fn status_msr(bank: usize) -> u32 { IA32_MC0_CTL + 4 * bank as u32 + 1 }
+fn addr_msr(bank: usize) -> u32 { IA32_MC0_CTL + 4 * bank as u32 + 2 }
+fn misc_msr(bank: usize) -> u32 { IA32_MC0_CTL + 4 * bank as u32 + 3 }
+
+/// Turn on error reporting in every bank, then CR4.MCE. Returns the
+/// number of banks, 0 if there's no machine check support.
+pub fn init() -> usize
+{
+    let features = cpu::cpuid(1, 0).edx;
+    let has_mce = features & (1 << 7) != 0;
+    let has_mca = features & (1 << 14) != 0;
+    if !has_mce {
+        return 0;
+    }
+
+    unsafe {
+        if has_mca {
+            let cap = rdmsr(IA32_MCG_CAP);
+            let banks = cmp::min((cap & MCG_BANK_COUNT) as usize, MAX_BANKS);
+            if cap & MCG_CTL_PRESENT != 0 {
+                wrmsr(IA32_MCG_CTL, !0);
+            }
+            for bank in 0..banks {
+                wrmsr(ctl_msr(bank), !0);
+                // Left over from before we booted.
+                wrmsr(status_msr(bank), 0);
+            }
+            BANKS = banks;
+        }
+        cr4_write(cr4() | CR4_MCE);
+        BANKS
+    }
+}
+
+/// An error logged in one bank.
+#[derive(Debug, Clone, Copy)]
+pub struct BankError {
+    pub bank: usize,
+    pub status: u64,
+    pub address: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl BankError {
+    /// Read bank `bank`, None if it has nothing logged.
+    fn read(bank: usize) -> Option<BankError>
+    {
+        let status = unsafe { rdmsr(status_msr(bank)) };
+        if status & STATUS_VALID == 0 {
+            return None;
+        }
+        Some(BankError {
+            bank: bank,
+            status: status,
+            address: if status & STATUS_ADDR_VALID != 0 {
+                Some(unsafe { rdmsr(addr_msr(bank)) })
+            } else {
+                None
+            },
+            misc: if status & STATUS_MISC_VALID != 0 {
+                Some(unsafe { rdmsr(misc_msr(bank)) })
+            } else {
+                None
+            },
+        })
+    }
+
+    fn clear(&self)
+    {
+        unsafe { wrmsr(status_msr(self.bank), 0) };
+    }
+
+    pub fn uncorrected(&self) -> bool
+    {
+        self.status & STATUS_UNCORRECTED != 0
+    }
+
+    pub fn context_corrupt(&self) -> bool
+    {
+        self.status & STATUS_PCC != 0
+    }
+
+    /// It raised the #MC, rather than just being logged.
+    pub fn signalled(&self) -> bool
+    {
+        self.status & STATUS_ENABLED != 0
+    }
+
+    /// An earlier error in the bank was overwritten.
+    pub fn overflow(&self) -> bool
+    {
+        self.status & STATUS_OVERFLOW != 0
+    }
+
+    pub fn error_code(&self) -> u16
+    {
+        self.status as u16
+    }
+
+    pub fn model_code(&self) -> u16
+    {
+        (self.status >> 16) as u16
+    }
+
+    /// The class of the architectural error code.
+    pub fn kind(&self) -> &'static str
+    {
+        // Bit 12 is the corrected error filtering bit, not part of the class.
+        let code = self.error_code() & !0x1000;
+        match code {
+            0x0000 => "no error",
+            0x0001 => "unclassified",
+            0x0002 => "microcode ROM parity error",
+            0x0003 => "external error",
+            0x0004 => "FRC error",
+            0x0005 => "internal parity error",
+            0x0400 => "internal timer error",
+            _ if code & 0xf800 == 0x0800 => "bus or interconnect error",
+            _ if code & 0xff80 == 0x0080 => "memory controller error",
+            _ if code & 0xff00 == 0x0100 => "cache hierarchy error",
+            _ if code & 0xfff0 == 0x0010 => "TLB error",
+            _ if code & 0xfc00 == 0x0400 => "internal unclassified error",
+            _ => "unknown error",
+        }
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let corrected = if self.uncorrected() { "uncorrected" } else { "corrected" };
+        try!(write!(f, "Bank {}: {} {} (code {:#06x}, model {:#06x})",
+                    self.bank, corrected, self.kind(), self.error_code(), self.model_code()));
+        if self.context_corrupt() {
+            try!(write!(f, ", context corrupt"));
+        }
+        if self.overflow() {
+            try!(write!(f, ", overflowed"));
+        }
+        if !self.signalled() {
+            try!(write!(f, ", not signalled"));
+        }
+        try!(writeln!(f, ""));
+        try!(write!(f, "  STATUS={:016x}", self.status));
+        if let Some(address) = self.address {
+            try!(write!(f, " ADDR={:016x}", address));
+        }
+        if let Some(misc) = self.misc {
+            try!(write!(f, " MISC={:016x}", misc));
+        }
+        writeln!(f, "")
+    }
+}
+
+/// Everything logged in the banks at one point.
+pub struct Report {
+    pub mcg_status: u64,
+    pub errors: [Option<BankError>; MAX_BANKS],
+}
+
+impl Report {
+    /// Read every bank. Reading doesn't clear them, see `clear`.
+    pub fn read() -> Report
+    {
+        let mut report = Report {
+            mcg_status: unsafe { rdmsr(IA32_MCG_STATUS) },
+            errors: [None; MAX_BANKS],
+        };
+        for bank in 0..unsafe { BANKS } {
+            report.errors[bank] = BankError::read(bank);
+        }
+        report
+    }
+
+    /// Whether execution can carry on after the #MC. Only if every
+    /// error was corrected, returning after an uncorrected one would
+    /// just redo the access that hit it.
+    pub fn recoverable(&self) -> bool
+    {
+        self.mcg_status & MCG_RIPV != 0 &&
+            !self.errors.iter().filter_map(|e| *e).any(|e| e.uncorrected())
+    }
+
+    /// Clear the logged errors so new ones can be logged.
+    pub fn clear(&self)
+    {
+        for error in self.errors.iter().filter_map(|e| *e) {
+            error.clear();
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let status = self.mcg_status;
+        try!(writeln!(f, "MCG_STATUS={:016x} (RIPV {}, EIPV {}, MCIP {})", status,
+                      status & MCG_RIPV != 0, status & MCG_EIPV != 0, status & MCG_MCIP != 0));
+        for error in self.errors.iter().filter_map(|e| *e) {
+            try!(write!(f, "{}", error));
+        }
+        Ok(())
+    }
+}
+
+/// The #MC handler.
+pub fn machine_check(stack: &InterruptStack)
+{
+    let report = Report::read();
+    report.clear();
+    if !report.recoverable() {
+        panic!("Machine check\n{}{}", report, stack);
+    }
+    // MCIP set means another #MC now is a shutdown.
+    unsafe { wrmsr(IA32_MCG_STATUS, 0) };
+    try_print(format_args!("Machine check, recovered\n{}", report));
+}
+
+/// Log and clear any corrected errors. Called now and then, they don't
+/// raise an exception.
+pub fn poll()
+{
+    for bank in 0..unsafe { BANKS } {
+        match BankError::read(bank) {
+            // Uncorrected ones are the #MC handler's.
+            Some(error) if !error.uncorrected() => {
+                CORRECTED.fetch_add(1, Ordering::Relaxed);
+                error.clear();
+                try_print(format_args!("Corrected machine check\n{}", error));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Corrected errors found by `poll`.
+pub fn corrected_count() -> usize
+{
+    CORRECTED.load(Ordering::Relaxed)
+}
+
+/// `poll` every `POLL_INTERVAL` from now on. Needs the clock and timers
+/// going.
+pub fn start_polling()
+{
+    if unsafe { BANKS } > 0 {
+        schedule_poll();
+    }
+}
+
+fn schedule_poll()
+{
+    // Only fails with every timer in use, then polling stops.
+    timer::add_timer(clock::now() + POLL_INTERVAL, poll_timer, 0).ok();
+}
+
+fn poll_timer(_: usize)
+{
+    poll();
+    schedule_poll();
+}
@@ -0,0 +1,169 @@
+
+//! Memory mapped device registers.
+//!
+//! Device memory is mapped uncached into its own window in the higher
+//! half. Firmware tables in ordinary RAM go through the same window
+//! but cached, with `map_ram`. The window is only handed out once,
+//! unmapping gives it back only if it's the last region mapped. That's
+//! enough for mapping something briefly to look at it, there's not
+//! many devices and they don't go away.
+
+use core::intrinsics::{volatile_load, volatile_store};
+use core::mem;
+use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use memory::mmiotrace;
+use memory::paging::{Mapper, Page, MapError, PhysicalAddress, VirtualAddress};
+use memory::paging::{EntryFlags, WRITABLE, NO_CACHE, WRITE_THROUGH, NO_EXECUTE};
+use spin::Mutex;
+
+/// Start of the MMIO window. It's P4 entry 509, just below the kernel.
+pub const MMIO_BASE: VirtualAddress = 0xffff_fe80_0000_0000;
+/// The window is a whole P4 entry.
+pub const MMIO_SIZE: usize = 512 * 1024 * 1024 * 1024;
+
+/// Next unused address in the MMIO window.
+static NEXT_ADDRESS: Mutex<VirtualAddress> = Mutex::new(MMIO_BASE);
+
+/// Some mapped device memory.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioRegion {
+    virtual_base: VirtualAddress,
+    physical_base: PhysicalAddress,
+    size: usize,
+}
+
+/// Map `size` bytes of device memory at `physical` into the MMIO window.
+///
+/// If mmiotrace is enabled the region is traced straight away.
+pub fn map<A>(physical: PhysicalAddress,
+              size: usize,
+              mapper: &mut Mapper,
+              allocator: &mut A)
+              -> Result<MmioRegion, MapError>
+    where A: FrameAllocator
+{
+    let region = try!(map_window(physical, size,
+                                 WRITABLE | NO_CACHE | WRITE_THROUGH | NO_EXECUTE,
+                                 mapper, allocator));
+    if mmiotrace::is_enabled() {
+        mmiotrace::trace(&region, mapper);
+    }
+    Ok(region)
+}
+
+/// Map `size` bytes of ordinary RAM read only and cached, for reading
+/// firmware tables. It's never traced.
+pub fn map_ram<A>(physical: PhysicalAddress,
+                  size: usize,
+                  mapper: &mut Mapper,
+                  allocator: &mut A)
+                  -> Result<MmioRegion, MapError>
+    where A: FrameAllocator
+{
+    map_window(physical, size, NO_EXECUTE, mapper, allocator)
+}
+
+fn map_window<A>(physical: PhysicalAddress,
+                 size: usize,
+                 flags: EntryFlags,
+                 mapper: &mut Mapper,
+                 allocator: &mut A)
+                 -> Result<MmioRegion, MapError>
+    where A: FrameAllocator
+{
+    assert!(size > 0, "mapping an empty MMIO region");
+    // Devices don't always start on a page boundary.
+    let first_frame = physical / PAGE_SIZE;
+    let last_frame = (physical + size - 1) / PAGE_SIZE;
+    let pages = last_frame - first_frame + 1;
+
+    let virtual_start = {
+        let mut next = NEXT_ADDRESS.lock();
+        let start = *next;
+        assert!(start + pages * PAGE_SIZE <= MMIO_BASE + MMIO_SIZE,
+                "MMIO window is full");
+        *next += pages * PAGE_SIZE;
+        start
+    };
+
+    for i in 0..pages {
+        let page = Page::containing_address(virtual_start + i * PAGE_SIZE);
+        let frame = Frame { number: first_frame + i };
+        if let Err(error) = mapper.map_to(page, frame, flags, allocator) {
+            // Undo the pages mapped so far and give the space back.
+            release(virtual_start, i, pages, mapper);
+            return Err(error);
+        }
+    }
+
+    Ok(MmioRegion {
+        virtual_base: virtual_start + physical % PAGE_SIZE,
+        physical_base: physical,
+        size: size,
+    })
+}
+
+/// Unmap a region from `map` or `map_ram`. Its part of the window is
+/// only reused if it was the last region mapped, otherwise the address
+/// space stays used.
+pub fn unmap(region: MmioRegion, mapper: &mut Mapper)
+{
+    mmiotrace::untrace(&region, mapper);
+
+    let start = region.virtual_base & !(PAGE_SIZE - 1);
+    let end = region.virtual_base + region.size;
+    let pages = (end - start + PAGE_SIZE - 1) / PAGE_SIZE;
+    release(start, pages, pages, mapper);
+}
+
+/// Unmap the first `mapped` pages of a `reserved` page stretch of the
+/// window starting at `start`, and give the stretch back if it's last.
+fn release(start: VirtualAddress, mapped: usize, reserved: usize, mapper: &mut Mapper)
+{
+    for i in 0..mapped {
+        let page = Page::containing_address(start + i * PAGE_SIZE);
+        // The frame isn't ours, there's nothing to free.
+        mapper.unmap_frame(page).expect("MMIO page went missing");
+    }
+
+    let mut next = NEXT_ADDRESS.lock();
+    if *next == start + reserved * PAGE_SIZE {
+        *next = start;
+    }
+}
+
+impl MmioRegion {
+    pub fn virtual_base(&self) -> VirtualAddress
+    {
+        self.virtual_base
+    }
+
+    pub fn physical_base(&self) -> PhysicalAddress
+    {
+        self.physical_base
+    }
+
+    pub fn size(&self) -> usize
+    {
+        self.size
+    }
+
+    fn register<T>(&self, offset: usize) -> *mut T
+    {
+        assert!(offset + mem::size_of::<T>() <= self.size,
+                "MMIO access at {:#x} is outside the region", offset);
+        (self.virtual_base + offset) as *mut T
+    }
+
+    /// Read the register at `offset`. Unsafe because reading device
+    /// registers can have side effects.
+    pub unsafe fn read<T: Copy>(&self, offset: usize) -> T
+    {
+        volatile_load(self.register(offset))
+    }
+
+    pub unsafe fn write<T: Copy>(&self, offset: usize, value: T)
+    {
+        volatile_store(self.register(offset), value)
+    }
+}
@@ -0,0 +1,487 @@
+
+//! MMIO tracing.
+//!
+//! Traced device pages are kept non-present. An access page faults,
+//! the fault handler decodes the instruction, makes the page present
+//! and single steps it with the trap flag. The debug exception after
+//! the step logs the access and makes the page non-present again.
+//!
+//! Only plain `mov`s (and `movzx`) are decoded. Which is what
+//! `MmioRegion::read` and `write` compile to.
+//!
+//! The interrupt entry code has to call `page_fault` and `single_step`
+//! with the interrupted state as a `Trapped`.
+
+use memory::PAGE_SIZE;
+use memory::mmio::MmioRegion;
+use memory::paging::{Mapper, Page, PhysicalAddress, VirtualAddress};
+use spin::Mutex;
+
+/// Most pages that can be traced at once.
+const MAX_TRACED_PAGES: usize = 64;
+/// Accesses kept in the ring buffer. Older ones are overwritten.
+const TRACE_BUFFER_SIZE: usize = 256;
+
+const TRAP_FLAG: u64 = 1 << 8;
+const INTERRUPT_FLAG: u64 = 1 << 9;
+
+static mut ENABLED: bool = false;
+
+/// The interrupted code's state as the fault and debug handlers see
+/// it. Changes are picked up when the handler returns.
+pub trait Trapped {
+    fn rip(&self) -> u64;
+
+    fn rflags(&self) -> u64;
+
+    fn set_rflags(&mut self, rflags: u64);
+
+    /// General purpose register by its encoding number, 4 is rsp.
+    fn register(&self, index: u8) -> u64;
+}
+
+#[derive(Clone, Copy)]
+struct TracedPage {
+    page: Page,
+    physical: PhysicalAddress,
+}
+
+static TRACED_PAGES: Mutex<[Option<TracedPage>; MAX_TRACED_PAGES]> =
+    Mutex::new([None; MAX_TRACED_PAGES]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    /// The instruction couldn't be decoded. The value is meaningless.
+    Unknown,
+}
+
+/// A logged access to a traced page.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioAccess {
+    pub kind: AccessKind,
+    pub address: PhysicalAddress,
+    /// Width in bytes.
+    pub width: u8,
+    pub value: u64,
+    pub rip: u64,
+}
+
+struct TraceBuffer {
+    entries: [Option<MmioAccess>; TRACE_BUFFER_SIZE],
+    /// Where the next access goes.
+    next: usize,
+    /// Accesses that were overwritten before being read.
+    lost: usize,
+}
+
+static TRACE: Mutex<TraceBuffer> = Mutex::new(TraceBuffer {
+    entries: [None; TRACE_BUFFER_SIZE],
+    next: 0,
+    lost: 0,
+});
+
+/// An access between the page fault and the single step.
+#[derive(Clone, Copy)]
+struct Pending {
+    page: Page,
+    address: PhysicalAddress,
+    instruction: Instruction,
+    rip: u64,
+    /// RFLAGS.IF before we cleared it for the step.
+    interrupts_enabled: bool,
+}
+
+static PENDING: Mutex<Option<Pending>> = Mutex::new(None);
+
+/// Trace every MMIO region mapped from now on.
+pub fn enable()
+{
+    unsafe { ENABLED = true; }
+}
+
+/// Stop tracing new regions. Already traced regions stay traced until
+/// `untrace`d.
+pub fn disable()
+{
+    unsafe { ENABLED = false; }
+}
+
+pub fn is_enabled() -> bool
+{
+    unsafe { ENABLED }
+}
+
+/// Start tracing a region. Pages over `MAX_TRACED_PAGES` are left alone.
+pub fn trace(region: &MmioRegion, mapper: &mut Mapper)
+{
+    let mut traced = TRACED_PAGES.lock();
+    let (start, end, physical_start) = page_range(region);
+    for address in (start..end).step_by(PAGE_SIZE) {
+        let page = Page::containing_address(address);
+        if traced.iter().any(|t| t.map_or(false, |t| t.page.start_address() == address)) {
+            continue;
+        }
+        let physical = physical_start + (address - start);
+        match traced.iter_mut().find(|t| t.is_none()) {
+            Some(slot) => *slot = Some(TracedPage { page: page, physical: physical }),
+            None => {
+                println!("mmiotrace: too many traced pages, {:#x} not traced",
+                         physical);
+                return;
+            }
+        }
+        mapper.set_present(page, false).expect("MMIO page went missing");
+    }
+}
+
+/// Stop tracing a region.
+pub fn untrace(region: &MmioRegion, mapper: &mut Mapper)
+{
+    let mut traced = TRACED_PAGES.lock();
+    let (start, end, _) = page_range(region);
+    for slot in traced.iter_mut() {
+        if let Some(t) = *slot {
+            let address = t.page.start_address();
+            if start <= address && address < end {
+                *slot = None;
+                mapper.set_present(t.page, true).expect("MMIO page went missing");
+            }
+        }
+    }
+}
+
+/// Page aligned virtual start and end of a region, and the physical
+/// address of the first page.
+fn page_range(region: &MmioRegion) -> (VirtualAddress, VirtualAddress, PhysicalAddress)
+{
+    let start = region.virtual_base() & !(PAGE_SIZE - 1);
+    let end = region.virtual_base() + region.size();
+    (start, end, region.physical_base() & !(PAGE_SIZE - 1))
+}
+
+fn find_traced(address: VirtualAddress) -> Option<TracedPage>
+{
+    let start = Page::containing_address(address).start_address();
+    TRACED_PAGES.lock().iter()
+        .filter_map(|t| *t)
+        .find(|t| t.page.start_address() == start)
+}
+
+/// Called from the page fault handler. Returns true if the fault was
+/// on a traced page, in which case the access is single stepped.
+pub fn page_fault<T: Trapped>(address: VirtualAddress, trapped: &mut T) -> bool
+{
+    let traced = match find_traced(address) {
+        Some(traced) => traced,
+        None => return false,
+    };
+
+    let instruction = unsafe { decode(trapped.rip() as *const u8) };
+    *PENDING.lock() = Some(Pending {
+        page: traced.page,
+        address: traced.physical + address % PAGE_SIZE,
+        instruction: instruction,
+        rip: trapped.rip(),
+        interrupts_enabled: trapped.rflags() & INTERRUPT_FLAG != 0,
+    });
+
+    // Safe as this is the only thing changing traced pages while the
+    // interrupted code is stopped.
+    let mut mapper = unsafe { Mapper::new() };
+    mapper.set_present(traced.page, true).expect("MMIO page went missing");
+
+    // Step the one instruction without being interrupted.
+    let rflags = trapped.rflags();
+    trapped.set_rflags((rflags | TRAP_FLAG) & !INTERRUPT_FLAG);
+    true
+}
+
+/// Called from the debug exception handler. Returns true if it was the
+/// step after a traced page fault.
+pub fn single_step<T: Trapped>(trapped: &mut T) -> bool
+{
+    let pending = match PENDING.lock().take() {
+        Some(pending) => pending,
+        None => return false,
+    };
+
+    let instruction = pending.instruction;
+    let value = match instruction.source {
+        Source::Register(index, shift) | Source::Destination(index, shift) =>
+            (trapped.register(index) >> shift) & width_mask(instruction.width),
+        Source::Immediate(value) => value & width_mask(instruction.width),
+        Source::None => 0,
+    };
+    log(MmioAccess {
+        kind: instruction.kind,
+        address: pending.address,
+        width: instruction.width,
+        value: value,
+        rip: pending.rip,
+    });
+
+    let mut mapper = unsafe { Mapper::new() };
+    mapper.set_present(pending.page, false).expect("MMIO page went missing");
+
+    let rflags = trapped.rflags() & !TRAP_FLAG;
+    if pending.interrupts_enabled {
+        trapped.set_rflags(rflags | INTERRUPT_FLAG);
+    } else {
+        trapped.set_rflags(rflags);
+    }
+    true
+}
+
+fn log(access: MmioAccess)
+{
+    let mut trace = TRACE.lock();
+    let next = trace.next;
+    if trace.entries[next].is_some() {
+        trace.lost += 1;
+    }
+    trace.entries[next] = Some(access);
+    trace.next = (next + 1) % TRACE_BUFFER_SIZE;
+}
+
+/// Remove logged accesses, oldest first, and hand them to `f`.
+pub fn drain<F>(mut f: F)
+    where F: FnMut(MmioAccess)
+{
+    let mut trace = TRACE.lock();
+    let start = trace.next;
+    for i in 0..TRACE_BUFFER_SIZE {
+        let index = (start + i) % TRACE_BUFFER_SIZE;
+        if let Some(access) = trace.entries[index].take() {
+            f(access);
+        }
+    }
+    trace.lost = 0;
+}
+
+/// Print and remove the logged accesses.
+pub fn print_trace()
+{
+    let lost = TRACE.lock().lost;
+    if lost > 0 {
+        println!("mmiotrace: {} accesses lost", lost);
+    }
+    drain(|access| {
+        let kind = match access.kind {
+            AccessKind::Read => "R",
+            AccessKind::Write => "W",
+            AccessKind::Unknown => "?",
+        };
+        println!("{} {} {:#12x} = {:#x} rip {:#x}",
+                 kind, access.width, access.address, access.value, access.rip);
+    });
+}
+
+// Instruction decoding.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    /// Value is in a register before and after the step. (index, shift)
+    Register(u8, u8),
+    /// Value is in a register after the step. (index, shift)
+    Destination(u8, u8),
+    Immediate(u64),
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Instruction {
+    kind: AccessKind,
+    width: u8,
+    source: Source,
+}
+
+const UNKNOWN: Instruction = Instruction {
+    kind: AccessKind::Unknown,
+    width: 0,
+    source: Source::None,
+};
+
+fn width_mask(width: u8) -> u64
+{
+    match width {
+        8 => !0,
+        width => (1u64 << (width * 8)) - 1,
+    }
+}
+
+/// Decode the instruction at `rip`. Unsafe as it reads from `rip`.
+unsafe fn decode(rip: *const u8) -> Instruction
+{
+    let byte = |i: usize| *rip.offset(i as isize);
+
+    let mut i = 0;
+    let mut operand_size_prefix = false;
+    let mut rex = 0;
+    loop {
+        match byte(i) {
+            0x66 => operand_size_prefix = true,
+            // String instructions aren't supported.
+            0xf2 | 0xf3 => return UNKNOWN,
+            prefix @ 0x40...0x4f => rex = prefix,
+            _ => break,
+        }
+        i += 1;
+    }
+    let rex_w = rex & 0x8 != 0;
+    let rex_r = rex & 0x4 != 0;
+
+    let word_width = if rex_w { 8 } else if operand_size_prefix { 2 } else { 4 };
+    let (kind, width, two_byte) = match byte(i) {
+        0x88 => (AccessKind::Write, 1, false),
+        0x89 => (AccessKind::Write, word_width, false),
+        0x8a => (AccessKind::Read, 1, false),
+        0x8b => (AccessKind::Read, word_width, false),
+        0xc6 => (AccessKind::Write, 1, false),
+        0xc7 => (AccessKind::Write, word_width, false),
+        0x0f => match byte(i + 1) {
+            0xb6 => (AccessKind::Read, 1, true),
+            0xb7 => (AccessKind::Read, 2, true),
+            _ => return UNKNOWN,
+        },
+        _ => return UNKNOWN,
+    };
+    let opcode = byte(i);
+    i += if two_byte { 2 } else { 1 };
+
+    let modrm = byte(i);
+    let reg = ((modrm >> 3) & 0x7) | if rex_r { 0x8 } else { 0 };
+
+    // Without a REX prefix byte registers 4-7 are ah, ch, dh and bh.
+    // movzx's destination isn't a byte register, only its source is.
+    let byte_register = opcode == 0x88 || opcode == 0x8a;
+    let (reg, shift) = if byte_register && rex == 0 && reg >= 4 {
+        (reg - 4, 8)
+    } else {
+        (reg, 0)
+    };
+
+    let source = match opcode {
+        0x88 | 0x89 => Source::Register(reg, shift),
+        0xc6 | 0xc7 => {
+            // A byte at a time, it needn't be aligned.
+            let start = i + modrm_length(rip.offset(i as isize));
+            let immediate = |size: usize| {
+                (0..size).fold(0u64, |value, n| value | (byte(start + n) as u64) << (8 * n))
+            };
+            Source::Immediate(match width {
+                // imm32 sign extended to 64 bits.
+                8 => immediate(4) as u32 as i32 as i64 as u64,
+                width => immediate(width as usize),
+            })
+        }
+        _ => Source::Destination(reg, shift),
+    };
+
+    Instruction { kind: kind, width: width, source: source }
+}
+
+/// Length of the ModRM byte and the SIB and displacement following it.
+unsafe fn modrm_length(modrm_pointer: *const u8) -> usize
+{
+    let modrm = *modrm_pointer;
+    let mode = modrm >> 6;
+    let rm = modrm & 0x7;
+    let has_sib = mode != 3 && rm == 4;
+
+    let displacement = match mode {
+        0 if rm == 5 => 4,
+        0 if has_sib && *modrm_pointer.offset(1) & 0x7 == 5 => 4,
+        1 => 1,
+        2 => 4,
+        _ => 0,
+    };
+    1 + if has_sib { 1 } else { 0 } + displacement
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, width_mask, AccessKind, Instruction, Source, UNKNOWN};
+
+    fn decoded(bytes: &[u8]) -> Instruction
+    {
+        unsafe { decode(bytes.as_ptr()) }
+    }
+
+    fn instruction(kind: AccessKind, width: u8, source: Source) -> Instruction
+    {
+        Instruction { kind: kind, width: width, source: source }
+    }
+
+    #[test]
+    fn register_moves()
+    {
+        // mov [rdi], eax
+        assert_eq!(decoded(&[0x89, 0x07]),
+                   instruction(AccessKind::Write, 4, Source::Register(0, 0)));
+        // mov [rdi], ax
+        assert_eq!(decoded(&[0x66, 0x89, 0x07]),
+                   instruction(AccessKind::Write, 2, Source::Register(0, 0)));
+        // mov rax, [rdi]
+        assert_eq!(decoded(&[0x48, 0x8b, 0x07]),
+                   instruction(AccessKind::Read, 8, Source::Destination(0, 0)));
+        // mov r9d, [rdi]
+        assert_eq!(decoded(&[0x44, 0x8b, 0x0f]),
+                   instruction(AccessKind::Read, 4, Source::Destination(9, 0)));
+    }
+
+    #[test]
+    fn byte_registers()
+    {
+        // mov [rdi], ah
+        assert_eq!(decoded(&[0x88, 0x27]),
+                   instruction(AccessKind::Write, 1, Source::Register(0, 8)));
+        // mov [rdi], spl
+        assert_eq!(decoded(&[0x40, 0x88, 0x27]),
+                   instruction(AccessKind::Write, 1, Source::Register(4, 0)));
+        // movzx esp, byte [rdi]
+        assert_eq!(decoded(&[0x0f, 0xb6, 0x27]),
+                   instruction(AccessKind::Read, 1, Source::Destination(4, 0)));
+        // movzx eax, word [rdi]
+        assert_eq!(decoded(&[0x0f, 0xb7, 0x07]),
+                   instruction(AccessKind::Read, 2, Source::Destination(0, 0)));
+    }
+
+    #[test]
+    fn immediates()
+    {
+        // mov dword [rdi+0x10], 0x12345678
+        assert_eq!(decoded(&[0xc7, 0x47, 0x10, 0x78, 0x56, 0x34, 0x12]),
+                   instruction(AccessKind::Write, 4, Source::Immediate(0x12345678)));
+        // mov qword [rip+0], -1
+        assert_eq!(decoded(&[0x48, 0xc7, 0x05, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]),
+                   instruction(AccessKind::Write, 8, Source::Immediate(!0)));
+        // mov byte [rax*4+0], 0xab
+        assert_eq!(decoded(&[0xc6, 0x04, 0x85, 0, 0, 0, 0, 0xab]),
+                   instruction(AccessKind::Write, 1, Source::Immediate(0xab)));
+        // mov word [rdi], 0xbeef
+        assert_eq!(decoded(&[0x66, 0xc7, 0x07, 0xef, 0xbe]),
+                   instruction(AccessKind::Write, 2, Source::Immediate(0xbeef)));
+    }
+
+    #[test]
+    fn unsupported()
+    {
+        // rep movsb
+        assert_eq!(decoded(&[0xf3, 0xa4]), UNKNOWN);
+        // add [rdi], eax
+        assert_eq!(decoded(&[0x01, 0x07]), UNKNOWN);
+        // movsx eax, byte [rdi]
+        assert_eq!(decoded(&[0x0f, 0xbe, 0x07]), UNKNOWN);
+    }
+
+    #[test]
+    fn width_masks()
+    {
+        assert_eq!(width_mask(1), 0xff);
+        assert_eq!(width_mask(2), 0xffff);
+        assert_eq!(width_mask(4), 0xffff_ffff);
+        assert_eq!(width_mask(8), !0);
+    }
+}
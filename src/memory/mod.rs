@@ -1,13 +1,42 @@
 
-use self::paging::PhysicalAddress;
+use self::paging::{PhysicalAddress, VirtualAddress};
 
+#[macro_use]
+pub mod ro_after_init;
 pub mod paging;
 pub mod area_frame_allocator;
+pub mod mmio;
+pub mod mmiotrace;
+pub mod user_access;
 pub use self::area_frame_allocator::*;
 pub use self::paging::test_paging;
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// Where the kernel is linked. The boot code maps the first GiB of
+/// physical memory here, and the kernel is loaded just above 1MiB.
+///
+/// Must match `KERNEL_OFFSET` in `linker.ld` and `memory_layout.inc`
+pub const KERNEL_OFFSET: usize = 0xffff_ff00_0000_0000;
+
+/// The physical address of something in the kernel's higher half.
+/// Addresses below `KERNEL_OFFSET` (the boot code) are linked at their
+/// physical address so they're returned as is.
+pub fn kernel_to_physical(address: VirtualAddress) -> PhysicalAddress
+{
+    if address >= KERNEL_OFFSET {
+        address - KERNEL_OFFSET
+    } else {
+        address
+    }
+}
+
+/// Where a physical address is in the kernel's higher half.
+pub fn physical_to_kernel(address: PhysicalAddress) -> VirtualAddress
+{
+    address + KERNEL_OFFSET
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Frame {
     number: usize,
@@ -15,6 +15,11 @@ impl Entry {
         self.0 = 0;
     }
 
+    /// The whole entry, frame address and flags.
+    pub fn raw(&self) -> u64 {
+        self.0
+    }
+
     pub fn flags(&self) -> EntryFlags {
         EntryFlags::from_bits_truncate(self.0)
     }
@@ -27,6 +32,17 @@ impl Entry {
         }
     }
 
+    /// Set or clear `PRESENT` and leave the rest of the entry alone.
+    /// The frame is kept, so a non-present entry can be made present
+    /// again later.
+    pub fn set_present(&mut self, present: bool) {
+        if present {
+            self.0 |= PRESENT.bits();
+        } else {
+            self.0 &= !PRESENT.bits();
+        }
+    }
+
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         assert!(frame.start_address() & !0x000f_ffff_ffff_f000 == 0);
         self.0 = (frame.start_address() as u64) | flags.bits();
@@ -0,0 +1,144 @@
+
+//! Explaining page faults.
+
+use core::fmt;
+use memory::ro_after_init;
+use spin::Mutex;
+use super::{Mapper, Page, VirtualAddress};
+use super::entry::{Entry, HUGE_PAGE};
+
+bitflags! {
+    /// The error code the CPU pushes for a page fault.
+    flags PageFaultError: u64 {
+        /// Set: the page was present but the access wasn't allowed.
+        /// Clear: the page wasn't present.
+        const PROTECTION_VIOLATION = 1 << 0,
+        const CAUSED_BY_WRITE      = 1 << 1,
+        const USER_MODE            = 1 << 2,
+        /// A reserved bit was set in one of the page table entries.
+        const MALFORMED_TABLE      = 1 << 3,
+        const INSTRUCTION_FETCH    = 1 << 4,
+        const PROTECTION_KEY       = 1 << 5,
+    }
+}
+
+const MAX_GUARD_PAGES: usize = 16;
+
+/// Pages deliberately left unmapped, and what they guard.
+static GUARD_PAGES: Mutex<[Option<(Page, &'static str)>; MAX_GUARD_PAGES]> =
+    Mutex::new([None; MAX_GUARD_PAGES]);
+
+/// Remember that `page` is a guard page so faults on it are reported
+/// as such.
+pub fn register_guard_page(page: Page, name: &'static str)
+{
+    let mut guards = GUARD_PAGES.lock();
+    match guards.iter_mut().find(|g| g.is_none()) {
+        Some(slot) => *slot = Some((page, name)),
+        None => println!("too many guard pages, {} not registered", name),
+    }
+}
+
+/// The name of the guard page containing `address`, if it's one.
+pub fn guard_page(address: VirtualAddress) -> Option<&'static str>
+{
+    let start = Page::containing_address(address).start_address();
+    // Don't deadlock if we faulted while registering.
+    GUARD_PAGES.try_lock().and_then(|guards| {
+        guards.iter()
+            .filter_map(|g| *g)
+            .find(|&(page, _)| page.start_address() == start)
+            .map(|(_, name)| name)
+    })
+}
+
+/// A page fault, displays as a description of what went wrong and the
+/// page table entries for the address.
+pub struct PageFault {
+    pub address: VirtualAddress,
+    pub error: PageFaultError,
+}
+
+impl PageFault {
+    pub fn new(address: VirtualAddress, error_code: u64) -> PageFault
+    {
+        PageFault {
+            address: address,
+            error: PageFaultError::from_bits_truncate(error_code),
+        }
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let error = self.error;
+        let who = if error.contains(USER_MODE) { "user" } else { "kernel" };
+        let access = if error.contains(INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if error.contains(CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let why = if error.contains(MALFORMED_TABLE) {
+            "reserved bit set in page table"
+        } else if error.contains(PROTECTION_KEY) {
+            "protection key violation"
+        } else if error.contains(PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "page not present"
+        };
+        try!(writeln!(f, "Page fault: {} {} at {:#x}, {} (error {:#x})",
+                      who, access, self.address, why, error.bits()));
+
+        if let Some(name) = guard_page(self.address) {
+            try!(writeln!(f, "Hit guard page: {}", name));
+        }
+        if ro_after_init::contains(self.address) {
+            try!(writeln!(f, "Address is in .data.ro_after_init"));
+        }
+
+        // The handler runs on the faulting address space, so this is
+        // the table that faulted.
+        let mapper = unsafe { Mapper::new() };
+        try!(write_walk(f, &mapper, Page::containing_address(self.address)));
+
+        match mapper.translate_page(Page::containing_address(self.address)) {
+            Some(frame) => writeln!(f, "Maps to frame {:#x}", frame.start_address()),
+            None => writeln!(f, "Not mapped"),
+        }
+    }
+}
+
+/// Write the P4 to P1 entries for `page`, stopping at the first one
+/// that isn't a table.
+fn write_walk(f: &mut fmt::Formatter, mapper: &Mapper, page: Page) -> fmt::Result
+{
+    let p4 = mapper.p4();
+    try!(write_entry(f, "P4", page.p4_index(), &p4[page.p4_index()]));
+    let p3 = match p4.next_table(page.p4_index()) {
+        Some(p3) => p3,
+        None => return Ok(()),
+    };
+    try!(write_entry(f, "P3", page.p3_index(), &p3[page.p3_index()]));
+    let p2 = match p3.next_table(page.p3_index()) {
+        Some(p2) => p2,
+        None => return Ok(()),
+    };
+    try!(write_entry(f, "P2", page.p2_index(), &p2[page.p2_index()]));
+    let p1 = match p2.next_table(page.p2_index()) {
+        Some(p1) => p1,
+        None => return Ok(()),
+    };
+    write_entry(f, "P1", page.p1_index(), &p1[page.p1_index()])
+}
+
+fn write_entry(f: &mut fmt::Formatter, level: &str, index: usize, entry: &Entry)
+               -> fmt::Result
+{
+    let huge = if entry.flags().contains(HUGE_PAGE) { " (huge)" } else { "" };
+    writeln!(f, "{}[{:3}] = {:#018x} {:?}{}",
+             level, index, entry.raw(), entry.flags(), huge)
+}
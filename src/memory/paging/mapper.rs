@@ -2,7 +2,7 @@
 
 use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
 use super::entry::*;
-use super::table::{self, Table, Level4};
+use super::table::{self, Table, Level4, Level1};
 use memory::{Frame, FrameAllocator};
 use core::ptr::Unique;
 
@@ -10,6 +10,20 @@ pub struct Mapper {
     p4: Unique<Table<Level4>>,
 }
 
+/// Reasons a mapping operation can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The frame allocator couldn't provide a frame for the page or
+    /// one of the page tables.
+    OutOfFrames,
+    /// The page already has a P1 entry.
+    AlreadyMapped,
+    /// A huge page sits where a page table is needed.
+    HugePageConflict,
+    /// The page isn't mapped (or is part of a huge page).
+    NotMapped,
+}
+
 impl Mapper
 {
     pub unsafe fn new() -> Mapper
@@ -78,48 +92,140 @@ impl Mapper
             .or_else(huge_page)
     }
 
+    /// Whether user mode may access `page`, which takes USER_ACCESSIBLE
+    /// at every level down to the one that maps it.
+    pub fn is_user_accessible(&self, page: Page) -> bool
+    {
+        let user = |entry: &Entry| entry.flags().contains(PRESENT | USER_ACCESSIBLE);
+        let huge = |entry: &Entry| entry.flags().contains(HUGE_PAGE);
+
+        let p4 = self.p4();
+        if !user(&p4[page.p4_index()]) {
+            return false;
+        }
+        let p3 = match p4.next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return false,
+        };
+        let p3_entry = &p3[page.p3_index()];
+        if !user(p3_entry) || huge(p3_entry) {
+            return user(p3_entry);
+        }
+        let p2 = match p3.next_table(page.p3_index()) {
+            Some(p2) => p2,
+            None => return false,
+        };
+        let p2_entry = &p2[page.p2_index()];
+        if !user(p2_entry) || huge(p2_entry) {
+            return user(p2_entry);
+        }
+        match p2.next_table(page.p2_index()) {
+            Some(p1) => user(&p1[page.p1_index()]),
+            None => false,
+        }
+    }
+
+    /// Map `page` to `frame`. On failure the frame is dropped, it's up
+    /// to the caller to have kept track of it if it needs it back.
     pub fn map_to<A>(&mut self,
                      page: Page,
                      frame: Frame,
                      flags: EntryFlags,
                      allocator: &mut A)
+                     -> Result<(), MapError>
         where A: FrameAllocator
     {
         let mut p4 = self.p4_mut();
-        let mut p3 = p4.next_table_create(page.p4_index(), allocator);
-        let mut p2 = p3.next_table_create(page.p3_index(), allocator);
-        let mut p1 = p2.next_table_create(page.p2_index(), allocator);
-        
-        assert!(p1[page.p1_index()].is_unused());
+        let mut p3 = try!(p4.next_table_create(page.p4_index(), allocator));
+        let mut p2 = try!(p3.next_table_create(page.p3_index(), allocator));
+        let mut p1 = try!(p2.next_table_create(page.p2_index(), allocator));
+
+        if !p1[page.p1_index()].is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
         p1[page.p1_index()].set(frame, flags | PRESENT);
+        Ok(())
     }
 
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
+                  -> Result<(), MapError>
         where A: FrameAllocator
     {
-        let frame = allocator.allocate_frame().expect("out of memory");
-        self.map_to(page, frame, flags, allocator);
+        let frame = try!(allocator.allocate_frame().ok_or(MapError::OutOfFrames));
+        let result = self.map_to(page, frame.clone(), flags, allocator);
+        if result.is_err() {
+            // Don't leak it if the page couldn't be mapped.
+            allocator.deallocate_frame(frame);
+        }
+        result
     }
 
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+                    -> Result<(), MapError>
         where A: FrameAllocator
     {
-        assert!(self.translate(page.start_address()).is_some());
-        let p1 = self.p4_mut()
-            .next_table_mut(page.p4_index())
-            .and_then(|p3| p3.next_table_mut(page.p3_index()))
-            .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("page lookup failed, or contained huge page");
-        let frame = p1[page.p1_index()].pointed_frame().unwrap();
+        let frame = try!(self.unmap_frame(page));
+        // Todo: free frames used by empty page tables
+        allocator.deallocate_frame(frame);
+        Ok(())
+    }
+
+    /// Unmap `page` and hand its frame back instead of freeing it, for
+    /// memory the allocator doesn't own like devices.
+    pub fn unmap_frame(&mut self, page: Page) -> Result<Frame, MapError>
+    {
+        let p1 = try!(self.p1_mut(page));
+        let frame = try!(p1[page.p1_index()].pointed_frame()
+                         .ok_or(MapError::NotMapped));
         p1[page.p1_index()].set_unused();
 
-        // TODO: Flush TLB
         unsafe {
             ::x86::tlb::flush(page.start_address())
         }
-        
-        // Todo: free frames used by empty page tables
-        allocator.deallocate_frame(frame);
+        Ok(frame)
+    }
+
+    /// Replace the flags of an already mapped page. `PRESENT` is always
+    /// kept set, use `unmap` to remove a page.
+    pub fn set_flags(&mut self, page: Page, flags: EntryFlags)
+                     -> Result<(), MapError>
+    {
+        let p1 = try!(self.p1_mut(page));
+        let frame = try!(p1[page.p1_index()].pointed_frame()
+                         .ok_or(MapError::NotMapped));
+        p1[page.p1_index()].set(frame, flags | PRESENT);
+
+        unsafe {
+            ::x86::tlb::flush(page.start_address())
+        }
+        Ok(())
+    }
+
+    /// Mark a page not present (or present again) without forgetting
+    /// which frame it points to.
+    pub fn set_present(&mut self, page: Page, present: bool)
+                       -> Result<(), MapError>
+    {
+        let p1 = try!(self.p1_mut(page));
+        if p1[page.p1_index()].is_unused() {
+            return Err(MapError::NotMapped);
+        }
+        p1[page.p1_index()].set_present(present);
+
+        unsafe {
+            ::x86::tlb::flush(page.start_address())
+        }
+        Ok(())
+    }
+
+    /// The P1 table containing `page`.
+    fn p1_mut(&mut self, page: Page) -> Result<&mut Table<Level1>, MapError>
+    {
+        self.p4_mut()
+            .next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .ok_or(MapError::NotMapped)
     }
 
     /// Identity map the the given frame with the provided flags.
@@ -128,10 +234,11 @@ impl Mapper
                            frame: Frame,
                            flags: EntryFlags,
                            allocator: &mut A)
+                           -> Result<(), MapError>
         where A: FrameAllocator
     {
         let addr: VirtualAddress = frame.start_address();
-        self.map_to(Page::containing_address(addr), frame, flags, allocator);
+        self.map_to(Page::containing_address(addr), frame, flags, allocator)
     }
 
 }
@@ -19,20 +19,28 @@
 //! Intel and AMD take very seriously.
 
 pub mod entry;
+pub mod fault;
 mod table;
 mod temporary_mapping;
 mod mapper;
 
 pub use self::entry::*;
-pub use self::mapper::Mapper;
+pub use self::mapper::{Mapper, MapError};
 use core::ops::{Deref, DerefMut};
-use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use memory::{PAGE_SIZE, KERNEL_OFFSET, Frame, FrameAllocator};
+use memory::{kernel_to_physical, physical_to_kernel};
+use vga::VGA_BUFFER;
 use multiboot2::BootInformation;
 use self::temporary_mapping::TemporaryPage;
 use x86::controlregs;
 use x86::tlb;
 
 
+extern {
+    /// Bottom of the boot stack, from boot.asm.
+    static stack_bottom: u8;
+}
+
 /// Number of entries in each page tables.
 const ENTRY_COUNT: usize = 512;
 
@@ -93,7 +101,8 @@ pub fn test_paging<A>(allocator: &mut A)
     println!("None = {:?}, map to {:?}",
              page_table.translate(addr),
              frame);
-    page_table.map_to(page, frame, EntryFlags::empty(), allocator);
+    page_table.map_to(page, frame, EntryFlags::empty(), allocator)
+        .expect("map_to failed");
     println!("Some = {:?}", page_table.translate(addr));
     println!("next free frame: {:?}", allocator.allocate_frame());
 
@@ -101,7 +110,8 @@ pub fn test_paging<A>(allocator: &mut A)
         *(Page::containing_address(addr).start_address() as *const u64)
     });
     
-    page_table.unmap(Page::containing_address(addr), allocator);
+    page_table.unmap(Page::containing_address(addr), allocator)
+        .expect("unmap failed");
     println!("None = {:?}", page_table.translate(addr));
 
     // println!("{:#x}", unsafe {
@@ -155,13 +165,17 @@ impl ActivePageTable {
             
     }
     
-    pub fn with<F>(&mut self,
-                   table: &mut InactivePageTable,
-                   temporary_page: &mut TemporaryPage,
-                   f: F)
-        where F: FnOnce(&mut Mapper)
+    /// Run `f` with the recursive mapping pointing at `table`. The
+    /// recursive mapping is always restored, even if `f` fails, and
+    /// `f`'s error is the one returned.
+    pub fn with<F, R>(&mut self,
+                      table: &mut InactivePageTable,
+                      temporary_page: &mut TemporaryPage,
+                      f: F)
+                      -> Result<R, MapError>
+        where F: FnOnce(&mut Mapper) -> Result<R, MapError>
     {
-        {
+        let result = {
             let flush_tlb = || unsafe { tlb::flush_all() };
         
             let backup = Frame::containing_address(unsafe {
@@ -171,20 +185,24 @@ impl ActivePageTable {
             });
 
             // map temporary_page to current p4 table
-            let p4_table = temporary_page.map_table_frame(backup.clone(), self);
+            let p4_table = try!(temporary_page.map_table_frame(backup.clone(), self));
 
             // overwrite recursive mapping
             self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
             flush_tlb();
 
             // Execute the callback with the new context.
-            f(self);
+            let result = f(self);
 
             // restore recursive mapping
             p4_table[511].set(backup, PRESENT | WRITABLE);
             flush_tlb();
-        }
-        temporary_page.unmap(self);
+            result
+        };
+        // It was mapped above, so failing to unmap it is a bug rather
+        // than something to hide `f`'s error behind.
+        temporary_page.unmap(self).expect("couldn't unmap the temporary page");
+        result
     }
 }
 
@@ -196,27 +214,32 @@ impl InactivePageTable {
     pub fn new(frame: Frame,
                active_table: &mut ActivePageTable,
                temporary_page: &mut TemporaryPage)
-               -> InactivePageTable
+               -> Result<InactivePageTable, MapError>
     {
         {
-            let table = temporary_page.map_table_frame(frame.clone(),
-                                                     active_table);
+            let table = try!(temporary_page.map_table_frame(frame.clone(),
+                                                            active_table));
             table.zero();
             table[511].set(frame.clone(), PRESENT | WRITABLE);
         }
-        temporary_page.unmap(active_table);
+        try!(temporary_page.unmap(active_table));
 
-        InactivePageTable { p4_frame: frame }
+        Ok(InactivePageTable { p4_frame: frame })
     }
 }
 
 /// Recreate the page table such that only the kernel, vga buffer, and
 /// multiboot structures are in memory. (And that they're properly
 /// protected).
+///
+/// Everything is mapped in the higher half, `multiboot_pos` is the
+/// physical start and end of the multiboot structures. The lower half
+/// is left empty, so the boot code's identity map is gone for good once
+/// this returns.
 pub fn remap_the_kernel<A>(allocator: &mut A,
-                           multiboot_pos: (usize, usize),
+                           multiboot_pos: (PhysicalAddress, PhysicalAddress),
                            boot_info: &BootInformation)
-                           -> ActivePageTable
+                           -> Result<ActivePageTable, MapError>
     where A: FrameAllocator
 {
     use core::ops::Range;
@@ -229,12 +252,12 @@ pub fn remap_the_kernel<A>(allocator: &mut A,
 
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
-        let frame = allocator.allocate_frame().expect("no more frame");
-        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
+        let frame = try!(allocator.allocate_frame().ok_or(MapError::OutOfFrames));
+        try!(InactivePageTable::new(frame, &mut active_table, &mut temporary_page))
     };
 
     println!("Switch recursive mapping");
-    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+    try!(active_table.with(&mut new_table, &mut temporary_page, |mapper| {
         let elf_sections_tag = boot_info.elf_sections_tag()
             .expect("Memory map tag required");
 
@@ -247,6 +270,11 @@ pub fn remap_the_kernel<A>(allocator: &mut A,
                 continue;
             }
 
+            if (section.addr as usize) < KERNEL_OFFSET {
+                // Boot code, only needed until we reach the higher half.
+                continue;
+            }
+
             println!("mapping section at addr: {:#x}, size: {:#x}",
                      section.addr, section.size);
 
@@ -261,32 +289,47 @@ pub fn remap_the_kernel<A>(allocator: &mut A,
             for address in range.step_by(PAGE_SIZE) {
                 assert!(address % PAGE_SIZE == 0,
                         "sections need to be page aligned");
-                let frame = Frame::containing_address(address);
-                mapper.identity_map(frame, flags, allocator);
+                let page = Page::containing_address(address);
+                let frame = Frame::containing_address(kernel_to_physical(address));
+                try!(mapper.map_to(page, frame, flags, allocator));
             }
         }
 
         println!("Remapping VGA buffer");
-        // Identity map the VGA text buffer.
-        let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, WRITABLE | NO_EXECUTE, allocator);
+        let vga_buffer_page = Page::containing_address(physical_to_kernel(VGA_BUFFER));
+        let vga_buffer_frame = Frame::containing_address(VGA_BUFFER);
+        try!(mapper.map_to(vga_buffer_page, vga_buffer_frame,
+                           WRITABLE | NO_EXECUTE, allocator));
 
         // Remapping multiboot.
         println!("Remapping Multiboot");
         let range = (multiboot_pos.0)..(multiboot_pos.1);
         for address in range.step_by(PAGE_SIZE) {
+            let page = Page::containing_address(physical_to_kernel(address));
             let frame = Frame::containing_address(address);
-            mapper.identity_map(frame, NO_EXECUTE, allocator);
+            try!(mapper.map_to(page, frame, NO_EXECUTE, allocator));
         }
-    });
+        Ok(())
+    }));
 
     let old_table = active_table.switch(new_table);
     println!("NEW TABLE!!!");
 
-    // Change the old p4 page into a guard page.
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
+    // Change the old p4 page into a guard page. It's in the kernel's
+    // .bss so it's now only mapped in the higher half.
+    let old_p4_page = Page::containing_address(
+        physical_to_kernel(old_table.p4_frame.start_address()));
+    try!(active_table.unmap(old_p4_page, allocator));
+    fault::register_guard_page(old_p4_page, "old boot P4 table");
     println!("guard page at {:#x}", old_p4_page.start_address());
 
-    active_table
+    // The page below the boot stack is an unused page table. Unmap it
+    // so an overflow faults instead of running into the page tables.
+    let stack_guard_page = Page::containing_address(
+        unsafe { &stack_bottom as *const u8 as usize } - PAGE_SIZE);
+    try!(active_table.unmap(stack_guard_page, allocator));
+    fault::register_guard_page(stack_guard_page, "kernel stack guard");
+    println!("stack guard page at {:#x}", stack_guard_page.start_address());
+
+    Ok(active_table)
 }
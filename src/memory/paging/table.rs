@@ -4,6 +4,7 @@ use memory::paging::ENTRY_COUNT;
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 use memory::FrameAllocator;
+use super::MapError;
 
 // Phantom type for Page level
 pub trait TableLevel {}
@@ -59,17 +60,18 @@ impl<L> Table<L> where L: HierarchicalLevel
     pub fn next_table_create<A>(&mut self,
                                 index: usize,
                                 allocator: &mut A)
-                                -> &mut Table<L::NextLevel>
+                                -> Result<&mut Table<L::NextLevel>, MapError>
         where A: FrameAllocator
     {
         if self.next_table(index).is_none() {
-            assert!(!self.entries[index].flags().contains(HUGE_PAGE),
-                    "huge pages not supported");
-            let frame = allocator.allocate_frame().expect("out of memory");
+            if self.entries[index].flags().contains(HUGE_PAGE) {
+                return Err(MapError::HugePageConflict);
+            }
+            let frame = try!(allocator.allocate_frame().ok_or(MapError::OutOfFrames));
             self.entries[index].set(frame, PRESENT | WRITABLE);
             self.next_table_mut(index).unwrap().zero();
         }
-        self.next_table_mut(index).unwrap()
+        Ok(self.next_table_mut(index).unwrap())
     }
 }
 
@@ -92,3 +94,44 @@ fn test() {
       .and_then(|p3| p3.next_table(1337))
       .and_then(|p2| p2.next_table(0xdeadbeaf));
 }
+
+#[cfg(test)]
+mod tests {
+    use core::mem;
+    use memory::{Frame, FrameAllocator};
+    use memory::paging::MapError;
+    use memory::paging::entry::*;
+    use super::{Table, Level4};
+
+    struct NoFrames;
+
+    impl FrameAllocator for NoFrames {
+        fn allocate_frame(&mut self) -> Option<Frame> { None }
+        fn deallocate_frame(&mut self, _frame: Frame) {}
+    }
+
+    // Only paths that fail before following the recursive mapping.
+    fn empty_table() -> Table<Level4>
+    {
+        unsafe { mem::zeroed() }
+    }
+
+    #[test]
+    fn next_table_create_out_of_frames()
+    {
+        let mut table = empty_table();
+        assert_eq!(table.next_table_create(3, &mut NoFrames).err(),
+                   Some(MapError::OutOfFrames));
+        assert!(table[3].is_unused());
+    }
+
+    #[test]
+    fn next_table_create_huge_page()
+    {
+        let mut table = empty_table();
+        table[3].set(Frame { number: 512 }, PRESENT | HUGE_PAGE);
+        assert_eq!(table.next_table_create(3, &mut NoFrames).err(),
+                   Some(MapError::HugePageConflict));
+        assert_eq!(table[3].raw(), (512 * 4096) as u64 | (PRESENT | HUGE_PAGE).bits());
+    }
+}
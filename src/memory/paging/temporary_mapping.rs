@@ -1,5 +1,5 @@
 
-use super::{Page, ActivePageTable, VirtualAddress};
+use super::{Page, ActivePageTable, VirtualAddress, MapError};
 use super::table::{Table, Level1};
 use memory::{Frame, FrameAllocator};
 
@@ -22,25 +22,25 @@ impl TemporaryPage {
     }
     
     pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable)
-               -> VirtualAddress
+               -> Result<VirtualAddress, MapError>
     {
         use super::entry::WRITABLE;
 
-        assert!(active_table.translate_page(self.page).is_none(),
-                "temporary page is already mapped");
-        active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator);
-        self.page.start_address()
+        try!(active_table.map_to(self.page, frame, WRITABLE, &mut self.allocator));
+        Ok(self.page.start_address())
     }
 
     pub fn map_table_frame(&mut self,
                            frame: Frame,
                            active_table: &mut ActivePageTable)
-                           -> &mut Table<Level1>
+                           -> Result<&mut Table<Level1>, MapError>
     {
-        unsafe { &mut *(self.map(frame, active_table) as *mut Table<Level1>) }
+        let address = try!(self.map(frame, active_table));
+        Ok(unsafe { &mut *(address as *mut Table<Level1>) })
     }
 
     pub fn unmap(&mut self, active_table: &mut ActivePageTable)
+                 -> Result<(), MapError>
     {
         active_table.unmap(self.page, &mut self.allocator)
     }
@@ -0,0 +1,62 @@
+//! Data that's written during boot and never again.
+//!
+//! Statics declared with `ro_after_init!` are put in the
+//! `.data.ro_after_init` section. It's writable until `protect` is
+//! called at the end of boot, after that any write page faults.
+
+use memory::PAGE_SIZE;
+use memory::paging::{ActivePageTable, Page, MapError, VirtualAddress, NO_EXECUTE};
+
+/// Put statics in `.data.ro_after_init`, they become read only once
+/// boot completes.
+///
+/// ```ignore
+/// ro_after_init! {
+///     static mut TIMER_VECTOR: u8 = 0;
+///     pub static NAMES: [&'static str; 2] = ["a", "b"];
+/// }
+/// ```
+macro_rules! ro_after_init {
+    ($($item:item)*) => (
+        $(
+            #[link_section = ".data.ro_after_init"]
+            $item
+        )*
+    );
+}
+
+extern {
+    // Defined in linker.ld, both are page aligned.
+    static __ro_after_init_start: u8;
+    static __ro_after_init_end: u8;
+}
+
+/// Remap the `.data.ro_after_init` pages read only.
+///
+/// Call this once boot has finished writing to them.
+pub fn protect(active_table: &mut ActivePageTable) -> Result<(), MapError>
+{
+    let (start, end) = bounds();
+
+    for address in (start..end).step_by(PAGE_SIZE) {
+        try!(active_table.set_flags(Page::containing_address(address), NO_EXECUTE));
+    }
+
+    println!("ro_after_init: {} pages now read only", (end - start) / PAGE_SIZE);
+    Ok(())
+}
+
+/// Is `address` in the `.data.ro_after_init` section.
+pub fn contains(address: VirtualAddress) -> bool
+{
+    let (start, end) = bounds();
+    start <= address && address < end
+}
+
+fn bounds() -> (VirtualAddress, VirtualAddress)
+{
+    unsafe {
+        (&__ro_after_init_start as *const u8 as usize,
+         &__ro_after_init_end as *const u8 as usize)
+    }
+}
@@ -0,0 +1,139 @@
+
+//! Keeping the kernel out of user memory.
+//!
+//! With SMEP the kernel can't execute user pages, and with SMAP it
+//! can't read or write them unless RFLAGS.AC is set. Deliberate
+//! accesses go through `with_user_access`, which sets AC with `stac`
+//! for as long as it runs.
+
+use core::fmt;
+use cpu;
+use memory::paging::{Mapper, Page, VirtualAddress};
+use memory::paging::fault::{PageFaultError, PROTECTION_VIOLATION, USER_MODE,
+                            INSTRUCTION_FETCH};
+use x86::controlregs::{cr4, cr4_write};
+
+const CR4_SMEP: usize = 1 << 20;
+const CR4_SMAP: usize = 1 << 21;
+
+const RFLAGS_AC: u64 = 1 << 18;
+
+static mut SMEP_ENABLED: bool = false;
+static mut SMAP_ENABLED: bool = false;
+
+/// Turn on SMEP and SMAP if the CPU has them.
+pub fn enable_protection()
+{
+    let mut bits = 0;
+    if cpu::has_smep() {
+        bits |= CR4_SMEP;
+    }
+    if cpu::has_smap() {
+        bits |= CR4_SMAP;
+    }
+    unsafe {
+        cr4_write(cr4() | bits);
+        SMEP_ENABLED = bits & CR4_SMEP != 0;
+        SMAP_ENABLED = bits & CR4_SMAP != 0;
+    }
+}
+
+pub fn smep_enabled() -> bool
+{
+    unsafe { SMEP_ENABLED }
+}
+
+pub fn smap_enabled() -> bool
+{
+    unsafe { SMAP_ENABLED }
+}
+
+/// Lower half addresses belong to user processes.
+pub fn is_user_address(address: VirtualAddress) -> bool
+{
+    address < 0x0000_8000_0000_0000
+}
+
+/// Allows the kernel to access user memory until dropped.
+pub struct UserAccess {
+    /// AC was already set, by a `UserAccess` further up this stack.
+    /// Interrupts start with it clear, so this is per context.
+    nested: bool,
+}
+
+impl UserAccess {
+    pub fn new() -> UserAccess
+    {
+        let nested = cpu::rflags() & RFLAGS_AC != 0;
+        if smap_enabled() && !nested {
+            unsafe { asm!("stac" :::: "volatile") };
+        }
+        UserAccess { nested: nested }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self)
+    {
+        if smap_enabled() && !self.nested {
+            unsafe { asm!("clac" :::: "volatile") };
+        }
+    }
+}
+
+/// Run `f` with access to user memory. Keep it to copying data in or
+/// out of user memory.
+pub fn with_user_access<F, R>(f: F) -> R
+    where F: FnOnce() -> R
+{
+    let _access = UserAccess::new();
+    f()
+}
+
+/// A page fault caused by SMEP or SMAP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// The kernel tried to execute a user page.
+    Smep,
+    /// The kernel touched a user page outside of `with_user_access`.
+    Smap,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            Violation::Smep => write!(f, "SMEP violation (kernel executed user memory)"),
+            Violation::Smap => write!(f, "SMAP violation (kernel accessed user memory)"),
+        }
+    }
+}
+
+/// Work out if a page fault was SMEP or SMAP's doing. Both show up as
+/// a protection fault by the kernel on a user page.
+pub fn classify_fault(address: VirtualAddress,
+                      error_code: u64,
+                      rflags: u64)
+                      -> Option<Violation>
+{
+    let error = PageFaultError::from_bits_truncate(error_code);
+    let kernel_protection_fault =
+        error.contains(PROTECTION_VIOLATION) && !error.contains(USER_MODE);
+    if !kernel_protection_fault || !is_user_address(address) {
+        return None;
+    }
+    // Lower half pages can still be kernel only, like the temporary
+    // page. Faults on those are plain protection faults.
+    let mapper = unsafe { Mapper::new() };
+    if !mapper.is_user_accessible(Page::containing_address(address)) {
+        return None;
+    }
+
+    if error.contains(INSTRUCTION_FETCH) {
+        if smep_enabled() { Some(Violation::Smep) } else { None }
+    } else if smap_enabled() && rflags & RFLAGS_AC == 0 {
+        Some(Violation::Smap)
+    } else {
+        None
+    }
+}
@@ -8,6 +8,8 @@
 use core::cell::UnsafeCell;
 use core::sync::atomic::{AtomicBool, Ordering};
 use core::ops::{Deref, DerefMut};
+use cpu;
+use x86::irq::{enable, disable};
 
 pub struct Mutex<T> {
     lock: AtomicBool,
@@ -95,3 +97,88 @@ impl<'a, T> Drop for LockGuard<'a, T>
         self.locked.lock.store(false, Ordering::SeqCst)
     }
 }
+
+/// A `Mutex` that also disables interrupts while it's held, so
+/// interrupt handlers can take it without deadlocking against the code
+/// they interrupted. The interrupt flag is put back as it was on unlock.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqMutex<T>
+{
+    pub const fn new(value: T) -> IrqMutex<T> {
+        IrqMutex { inner: Mutex::new(value) }
+    }
+
+    pub fn try_lock<'a>(&'a self) -> Option<IrqLockGuard<'a, T>>
+    {
+        let rflags = cpu::rflags();
+        unsafe { disable() };
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqLockGuard { guard: Some(guard), rflags: rflags }),
+            None => {
+                restore_interrupts(rflags);
+                None
+            }
+        }
+    }
+
+    pub fn lock<'a>(&'a self) -> IrqLockGuard<'a, T>
+    {
+        loop {
+            if let Some(lock) = self.try_lock() {
+                return lock;
+            }
+        }
+    }
+
+    /// See `Mutex::force_lock`.
+    pub unsafe fn force_lock<'a>(&'a self) -> IrqLockGuard<'a, T>
+    {
+        let rflags = cpu::rflags();
+        disable();
+        IrqLockGuard { guard: Some(self.inner.force_lock()), rflags: rflags }
+    }
+}
+
+fn restore_interrupts(rflags: u64)
+{
+    if rflags & cpu::INTERRUPT_FLAG != 0 {
+        unsafe { enable() };
+    }
+}
+
+pub struct IrqLockGuard<'a, T: 'a> {
+    /// Only None while dropping.
+    guard: Option<LockGuard<'a, T>>,
+    /// RFLAGS from before locking.
+    rflags: u64,
+}
+
+impl<'a, T> Deref for IrqLockGuard<'a, T>
+{
+    type Target = T;
+    fn deref(&self) -> &T
+    {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqLockGuard<'a, T>
+{
+    fn deref_mut(&mut self) -> &mut T
+    {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqLockGuard<'a, T>
+{
+    fn drop(&mut self)
+    {
+        // Unlock before interrupts can come back in.
+        self.guard.take();
+        restore_interrupts(self.rflags);
+    }
+}
@@ -0,0 +1,66 @@
+
+//! Monotonic time since boot.
+//!
+//! Read from the best clock source there is, in order: an invariant
+//! TSC, the HPET, then PIT ticks. The TSC and HPET are read without
+//! port I/O or locks.
+
+use super::{hpet, pit, tsc, NANOS_PER_SECOND};
+
+/// A free running counter.
+pub trait ClockSource {
+    fn name(&self) -> &'static str;
+
+    /// The counter, it only goes up.
+    fn read(&self) -> u64;
+
+    /// Counter rate in Hz.
+    fn frequency(&self) -> u64;
+}
+
+ro_after_init! {
+    static mut SOURCE: &'static ClockSource = &pit::PitClock;
+    /// Counter and uptime when the source was picked, so there's no jump.
+    static mut COUNTER_BASE: u64 = 0;
+    static mut NANOS_BASE: u64 = 0;
+}
+
+/// Pick the clock source. The PIT has to be running, the TSC calibrated
+/// and the HPET found if there is one.
+pub fn init() -> &'static ClockSource
+{
+    let source: &'static ClockSource = if tsc::is_invariant() && tsc::frequency() != 0 {
+        &tsc::TscClock
+    } else if hpet::is_usable_clock() {
+        &hpet::HpetClock
+    } else {
+        &pit::PitClock
+    };
+    unsafe {
+        NANOS_BASE = pit::uptime_nanos();
+        COUNTER_BASE = source.read();
+        SOURCE = source;
+    }
+    source
+}
+
+pub fn source() -> &'static ClockSource
+{
+    unsafe { SOURCE }
+}
+
+/// Convert `count` ticks of a `frequency` Hz counter to nanoseconds.
+pub fn to_nanos(count: u64, frequency: u64) -> u64
+{
+    // Split up so it doesn't overflow.
+    count / frequency * NANOS_PER_SECOND + count % frequency * NANOS_PER_SECOND / frequency
+}
+
+/// Nanoseconds since boot.
+pub fn now() -> u64
+{
+    let source = source();
+    unsafe {
+        NANOS_BASE + to_nanos(source.read() - COUNTER_BASE, source.frequency())
+    }
+}
@@ -0,0 +1,399 @@
+
+//! High Precision Event Timer.
+//!
+//! Found through the ACPI "HPET" table. Its main counter is a clock
+//! source, and each comparator can raise an interrupt once or
+//! periodically. Comparator interrupts go through the I/O APIC, so
+//! they need APIC mode.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use acpi;
+use acpi::madt::{Polarity, TriggerMode};
+use irq::{self, IrqHandler, RegisterError};
+use memory::FrameAllocator;
+use memory::mmio::{self, MmioRegion};
+use memory::paging::{Mapper, MapError};
+use spin::IrqMutex;
+use super::clock::ClockSource;
+
+// General registers.
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+/// Bit n is set while level triggered timer n is interrupting. Write 1
+/// to clear it.
+const INTERRUPT_STATUS: usize = 0x020;
+const MAIN_COUNTER: usize = 0x0f0;
+const REGISTERS_SIZE: usize = 0x400;
+
+// Capabilities.
+const COUNTER_64_BIT: u64 = 1 << 13;
+/// Period is in femtoseconds, at most 100ns.
+const MAX_PERIOD: u64 = 100_000_000;
+
+// Configuration.
+const ENABLE: u64 = 1 << 0;
+/// Legacy replacement, timers 0 and 1 take over IRQ 0 and 8 from the
+/// PIT and RTC.
+const LEGACY_ROUTE: u64 = 1 << 1;
+
+// Timer registers, per timer.
+const TIMER_CONFIG: usize = 0x100;
+const TIMER_COMPARATOR: usize = 0x108;
+const TIMER_STRIDE: usize = 0x20;
+const MAX_TIMERS: usize = 32;
+
+// Timer configuration.
+/// Level triggered interrupts, edge if clear.
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// Lets the next comparator write set the accumulator in periodic mode.
+const TIMER_SET_VALUE: u64 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+
+const FEMTOS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// No "HPET" ACPI table.
+    NoTable,
+    /// The registers aren't in memory, or the period is nonsense.
+    Unsupported,
+    Map(MapError),
+}
+
+impl From<MapError> for HpetError {
+    fn from(error: MapError) -> HpetError
+    {
+        HpetError::Map(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteError {
+    /// None of the inputs the timer can use are free IRQ lines.
+    NoFreeLine,
+    Register(RegisterError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerTestError {
+    Route(RouteError),
+    /// The one-shot interrupt never came.
+    NoOneShot,
+    /// Periodic mode didn't keep interrupting.
+    NoPeriodic,
+}
+
+#[derive(Clone, Copy)]
+struct Hpet {
+    region: MmioRegion,
+    /// Femtoseconds per counter tick.
+    period: u64,
+    timers: u8,
+    counter_64_bit: bool,
+}
+
+ro_after_init! {
+    static mut HPET: Option<Hpet> = None;
+}
+
+/// Handlers of the level triggered timers, `level_interrupt` is
+/// registered on their lines instead.
+static LEVEL_HANDLERS: IrqMutex<[Option<IrqHandler>; MAX_TIMERS]> =
+    IrqMutex::new([None; MAX_TIMERS]);
+
+fn hpet() -> Option<Hpet>
+{
+    unsafe { HPET }
+}
+
+fn read(hpet: &Hpet, register: usize) -> u64
+{
+    unsafe { hpet.region.read(register) }
+}
+
+fn write(hpet: &Hpet, register: usize, value: u64)
+{
+    unsafe { hpet.region.write(register, value) }
+}
+
+/// Find the HPET, map it and start the main counter with every
+/// comparator off.
+pub fn init<A>(mapper: &mut Mapper, allocator: &mut A) -> Result<(), HpetError>
+    where A: FrameAllocator
+{
+    let table = try!(acpi::find_table(b"HPET", mapper, allocator).ok_or(HpetError::NoTable));
+    // The base is a Generic Address Structure, space 0 is memory.
+    let (space, base) = (table.u8(40), table.u64(44) as usize);
+    acpi::unmap(table, mapper);
+    if space != 0 {
+        return Err(HpetError::Unsupported);
+    }
+    let region = try!(mmio::map(base, REGISTERS_SIZE, mapper, allocator));
+
+    let capabilities = unsafe { region.read::<u64>(CAPABILITIES) };
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD {
+        return Err(HpetError::Unsupported);
+    }
+    let hpet = Hpet {
+        region: region,
+        period: period,
+        timers: ((capabilities >> 8) & 0x1f) as u8 + 1,
+        counter_64_bit: capabilities & COUNTER_64_BIT != 0,
+    };
+
+    // Firmware may have left legacy replacement on, which would steal
+    // the PIT's and RTC's interrupts and ignore the timers' routes.
+    let config = read(&hpet, CONFIGURATION) & !LEGACY_ROUTE;
+    write(&hpet, CONFIGURATION, config & !ENABLE);
+    for timer in 0..hpet.timers {
+        let register = TIMER_CONFIG + timer as usize * TIMER_STRIDE;
+        let timer_config = read(&hpet, register);
+        write(&hpet, register, timer_config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+    }
+    write(&hpet, MAIN_COUNTER, 0);
+    write(&hpet, CONFIGURATION, config | ENABLE);
+
+    unsafe { HPET = Some(hpet) };
+    Ok(())
+}
+
+pub fn is_present() -> bool
+{
+    hpet().is_some()
+}
+
+/// Only 64-bit counters are used as a clock, 32-bit ones wrap too often.
+pub fn is_usable_clock() -> bool
+{
+    hpet().map_or(false, |hpet| hpet.counter_64_bit)
+}
+
+/// The main counter.
+pub fn counter() -> u64
+{
+    let hpet = hpet().expect("HPET used before init");
+    read(&hpet, MAIN_COUNTER)
+}
+
+/// Counter rate in Hz.
+pub fn frequency() -> u64
+{
+    let hpet = hpet().expect("HPET used before init");
+    FEMTOS_PER_SECOND / hpet.period
+}
+
+/// Counter ticks in `nanos` nanoseconds.
+pub fn nanos_to_ticks(nanos: u64) -> u64
+{
+    let hpet = hpet().expect("HPET used before init");
+    nanos / hpet.period * 1_000_000 + nanos % hpet.period * 1_000_000 / hpet.period
+}
+
+pub struct HpetClock;
+
+impl ClockSource for HpetClock {
+    fn name(&self) -> &'static str
+    {
+        "HPET"
+    }
+
+    fn read(&self) -> u64
+    {
+        counter()
+    }
+
+    fn frequency(&self) -> u64
+    {
+        frequency()
+    }
+}
+
+pub fn timer_count() -> u8
+{
+    hpet().map_or(0, |hpet| hpet.timers)
+}
+
+/// One of the HPET's comparators.
+pub struct Timer {
+    hpet: Hpet,
+    index: u8,
+}
+
+pub fn timer(index: u8) -> Option<Timer>
+{
+    hpet().and_then(|hpet| if index < hpet.timers {
+        Some(Timer { hpet: hpet, index: index })
+    } else {
+        None
+    })
+}
+
+impl Timer {
+    fn register(&self, offset: usize) -> usize
+    {
+        offset + self.index as usize * TIMER_STRIDE
+    }
+
+    fn config(&self) -> u64
+    {
+        read(&self.hpet, self.register(TIMER_CONFIG))
+    }
+
+    fn set_config(&self, config: u64)
+    {
+        write(&self.hpet, self.register(TIMER_CONFIG), config)
+    }
+
+    pub fn can_be_periodic(&self) -> bool
+    {
+        self.config() & TIMER_PERIODIC_CAPABLE != 0
+    }
+
+    /// Bit n set means it can interrupt on I/O APIC input n.
+    pub fn route_capability(&self) -> u32
+    {
+        (self.config() >> 32) as u32
+    }
+
+    /// Route the timer to a free IRQ line it's wired to and register
+    /// `handler` on it. Inputs that aren't ISA IRQs are given a line by
+    /// the controller. The timer is edge or level triggered to match
+    /// the input. Returns the line, for `disconnect`.
+    pub fn connect(&self, handler: IrqHandler) -> Result<u8, RouteError>
+    {
+        let capability = self.route_capability();
+        // Highest first, the low inputs are mostly ISA IRQs that are
+        // taken or might be wanted later. The HPET only drives its
+        // interrupts active high.
+        let found = (0..32).rev().filter(|&gsi| capability & (1 << gsi) != 0)
+            .filter_map(|gsi| irq::with_controller(|c| c.route_gsi(gsi)).map(|route| (gsi, route)))
+            .find(|&(_, route)| {
+                route.polarity == Polarity::ActiveHigh && !irq::dispatch::has_handlers(route.line)
+            });
+        let (gsi, route) = try!(found.ok_or(RouteError::NoFreeLine));
+
+        let mut config = self.config() & !(TIMER_ROUTE_MASK | TIMER_LEVEL);
+        config |= (gsi as u64) << TIMER_ROUTE_SHIFT;
+        let mut registered = handler;
+        if route.trigger == TriggerMode::Level {
+            LEVEL_HANDLERS.lock()[self.index as usize] = Some(handler);
+            config |= TIMER_LEVEL;
+            registered = level_interrupt;
+        }
+        self.set_config(config);
+        if let Err(error) = irq::register(route.line, registered) {
+            LEVEL_HANDLERS.lock()[self.index as usize] = None;
+            return Err(RouteError::Register(error));
+        }
+        Ok(route.line)
+    }
+
+    /// Stop the timer and take `handler` off `line`, undoing `connect`.
+    pub fn disconnect(&self, line: u8, handler: IrqHandler)
+    {
+        self.stop();
+        let level = LEVEL_HANDLERS.lock()[self.index as usize].take().is_some();
+        irq::unregister(line, if level { level_interrupt } else { handler });
+    }
+
+    /// Interrupt once when the main counter reaches `deadline`.
+    pub fn set_one_shot(&self, deadline: u64)
+    {
+        let config = self.config() & !TIMER_PERIODIC;
+        self.set_config(config & !TIMER_INTERRUPT_ENABLE);
+        write(&self.hpet, self.register(TIMER_COMPARATOR), deadline);
+        self.set_config(config | TIMER_INTERRUPT_ENABLE);
+    }
+
+    /// Interrupt every `period` counter ticks.
+    pub fn set_periodic(&self, period: u64)
+    {
+        assert!(self.can_be_periodic(), "HPET timer {} can't be periodic", self.index);
+        let config = self.config() & !TIMER_INTERRUPT_ENABLE;
+        self.set_config(config);
+        self.set_config(config | TIMER_PERIODIC | TIMER_SET_VALUE);
+        // First write is the first deadline, the second the period.
+        let comparator = self.register(TIMER_COMPARATOR);
+        write(&self.hpet, comparator, counter() + period);
+        write(&self.hpet, comparator, period);
+        self.set_config(config | TIMER_PERIODIC | TIMER_INTERRUPT_ENABLE);
+    }
+
+    pub fn stop(&self)
+    {
+        let config = self.config();
+        self.set_config(config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+    }
+}
+
+/// A level triggered timer keeps interrupting until its status bit is
+/// cleared, so this clears it before calling the timer's handler.
+fn level_interrupt()
+{
+    let hpet = hpet().expect("HPET interrupt before init");
+    let status = read(&hpet, INTERRUPT_STATUS);
+    let handlers = *LEVEL_HANDLERS.lock();
+    for (index, handler) in handlers.iter().enumerate() {
+        if let Some(handler) = *handler {
+            if status & (1 << index) != 0 {
+                write(&hpet, INTERRUPT_STATUS, 1 << index);
+                handler();
+            }
+        }
+    }
+}
+
+static TEST_FIRED: AtomicUsize = AtomicUsize::new(0);
+
+fn test_handler()
+{
+    TEST_FIRED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Spin for `ticks` counter ticks.
+fn spin(ticks: u64)
+{
+    let end = counter() + ticks;
+    while counter() < end {}
+}
+
+/// Connect timer 0 and check it interrupts, once and then periodically
+/// if it can. Interrupts have to be enabled. Returns the line it got.
+/// Only run with the `hpettest` feature.
+pub fn test_timer() -> Result<u8, TimerTestError>
+{
+    let timer = timer(0).expect("HPET tested before init");
+    let line = try!(timer.connect(test_handler).map_err(TimerTestError::Route));
+    let result = test_connected(&timer);
+    timer.disconnect(line, test_handler);
+    result.map(|()| line)
+}
+
+fn test_connected(timer: &Timer) -> Result<(), TimerTestError>
+{
+    let millisecond = nanos_to_ticks(1_000_000);
+
+    TEST_FIRED.store(0, Ordering::Relaxed);
+    timer.set_one_shot(counter() + millisecond);
+    spin(10 * millisecond);
+    if TEST_FIRED.load(Ordering::Relaxed) == 0 {
+        return Err(TimerTestError::NoOneShot);
+    }
+
+    if timer.can_be_periodic() {
+        TEST_FIRED.store(0, Ordering::Relaxed);
+        timer.set_periodic(millisecond);
+        spin(10 * millisecond);
+        timer.stop();
+        // Some slack for the first and last.
+        if TEST_FIRED.load(Ordering::Relaxed) < 5 {
+            return Err(TimerTestError::NoPeriodic);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,11 @@
+
+//! Keeping time.
+
+pub mod pit;
+pub mod tsc;
+pub mod hpet;
+pub mod clock;
+pub mod rtc;
+pub mod timer;
+
+pub const NANOS_PER_SECOND: u64 = 1_000_000_000;
@@ -0,0 +1,232 @@
+
+//! The 8253/8254 Programmable Interval Timer.
+//!
+//! Channel 0 raises IRQ 0 periodically, which is counted as ticks.
+//! Channel 2 is gated through port 0x61 and its output can be polled,
+//! so it's used as a one-shot for timing things without interrupts.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use irq;
+use port::Port;
+use spin::IrqMutex;
+use super::clock::{self, ClockSource};
+use super::{timer, NANOS_PER_SECOND};
+
+/// The PIT's input clock in Hz.
+pub const BASE_FREQUENCY: u64 = 1_193_182;
+/// Tick rate if nobody has a better idea.
+pub const DEFAULT_FREQUENCY: u32 = 100;
+/// Longest one-shot, a full 16-bit count.
+pub const MAX_ONE_SHOT_MICROS: u32 = 54_925;
+
+const TIMER_IRQ: u8 = 0;
+
+// Command byte fields.
+const SELECT_CHANNEL_0: u8 = 0b00 << 6;
+const SELECT_CHANNEL_2: u8 = 0b10 << 6;
+const ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+/// Output goes high once the count reaches 0.
+const MODE_TERMINAL_COUNT: u8 = 0 << 1;
+/// Output pulses every time the count reaches 0, and it reloads.
+const MODE_RATE_GENERATOR: u8 = 2 << 1;
+
+// Port 0x61 bits for channel 2.
+const CHANNEL_2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+
+struct Pit {
+    channel_0: Port<u8>,
+    channel_2: Port<u8>,
+    command: Port<u8>,
+    /// System control port B, has channel 2's gate and output.
+    control: Port<u8>,
+}
+
+static PIT: IrqMutex<Pit> = IrqMutex::new(Pit {
+    channel_0: unsafe { Port::new(0x40) },
+    channel_2: unsafe { Port::new(0x42) },
+    command: unsafe { Port::new(0x43) },
+    control: unsafe { Port::new(0x61) },
+});
+
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+/// Channel 0's reload value, 2 to 65536. Starts as the firmware's
+/// 18.2 Hz and is fixed once `init` sets it, so it's read without the
+/// lock.
+static DIVISOR: AtomicUsize = AtomicUsize::new(0x10000);
+
+/// Program channel 0 to tick at about `frequency` Hz and start counting
+/// ticks. The PIT can't hit most frequencies exactly, `frequency()`
+/// is the real one.
+pub fn init(frequency: u32)
+{
+    let divisor = divisor_for(frequency);
+    {
+        let mut pit = PIT.lock();
+        pit.command.write(SELECT_CHANNEL_0 | ACCESS_LOW_HIGH | MODE_RATE_GENERATOR);
+        pit.channel_0.write(divisor as u8);
+        pit.channel_0.write((divisor >> 8) as u8);
+    }
+    DIVISOR.store(divisor as usize, Ordering::Relaxed);
+    irq::register(TIMER_IRQ, tick).expect("IRQ 0 is taken");
+}
+
+fn divisor_for(frequency: u32) -> u32
+{
+    assert!(frequency > 0, "PIT frequency of 0");
+    let divisor = (BASE_FREQUENCY / frequency as u64) as u32;
+    // Mode 2 can't count from 1. 65536 is written as 0.
+    if divisor < 2 { 2 } else if divisor > 0xffff { 0x10000 } else { divisor }
+}
+
+fn tick()
+{
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    timer::tick();
+}
+
+/// Ticks since `init`.
+pub fn ticks() -> u64
+{
+    TICKS.load(Ordering::Relaxed) as u64
+}
+
+fn divisor() -> u64
+{
+    DIVISOR.load(Ordering::Relaxed) as u64
+}
+
+/// The actual tick rate, in mHz as it's rarely a whole number of Hz.
+pub fn frequency_millihertz() -> u64
+{
+    BASE_FREQUENCY * 1000 / divisor()
+}
+
+/// Nanoseconds since `init`, to the nearest tick.
+pub fn uptime_nanos() -> u64
+{
+    // In PIT input cycles to keep the rounding error down.
+    clock::to_nanos(ticks() * divisor(), BASE_FREQUENCY)
+}
+
+/// The first tick at or after `nanos` nanoseconds since `init`.
+pub fn ticks_for_nanos(nanos: u64) -> u64
+{
+    // Rounded up, both to input cycles and then to ticks.
+    let cycles = nanos / NANOS_PER_SECOND * BASE_FREQUENCY +
+        (nanos % NANOS_PER_SECOND * BASE_FREQUENCY + NANOS_PER_SECOND - 1) / NANOS_PER_SECOND;
+    let divisor = divisor();
+    (cycles + divisor - 1) / divisor
+}
+
+/// Ticks as a clock source, counted in input cycles.
+pub struct PitClock;
+
+impl ClockSource for PitClock {
+    fn name(&self) -> &'static str
+    {
+        "PIT"
+    }
+
+    fn read(&self) -> u64
+    {
+        ticks() * divisor()
+    }
+
+    fn frequency(&self) -> u64
+    {
+        BASE_FREQUENCY
+    }
+}
+
+/// Wait for `ticks` ticks, halting in between. Interrupts have to be
+/// enabled or it never returns.
+pub fn sleep_ticks(ticks: u64)
+{
+    assert!(::cpu::interrupts_enabled(), "sleep_ticks with interrupts disabled");
+    let end = self::ticks() + ticks;
+    while self::ticks() < end {
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
+
+/// Start channel 2 counting down from `micros` microseconds. Poll
+/// `one_shot_expired` to see when it's done. Calibrating other clocks
+/// against this is the main use.
+pub fn start_one_shot(micros: u32)
+{
+    assert!(micros <= MAX_ONE_SHOT_MICROS, "one-shot of {}us is too long", micros);
+    let count = ((micros as u64 * BASE_FREQUENCY + 999_999) / 1_000_000) as u32;
+    let count = if count == 0 { 1 } else { count };
+
+    let mut pit = PIT.lock();
+    // Gate off while it's programmed, and keep the speaker quiet.
+    let control = pit.control.read() & !(SPEAKER_ENABLE | CHANNEL_2_GATE);
+    pit.control.write(control);
+    pit.command.write(SELECT_CHANNEL_2 | ACCESS_LOW_HIGH | MODE_TERMINAL_COUNT);
+    // 65536 goes in as 0, like channel 0.
+    pit.channel_2.write(count as u8);
+    pit.channel_2.write((count >> 8) as u8);
+    pit.control.write(control | CHANNEL_2_GATE);
+}
+
+pub fn one_shot_expired() -> bool
+{
+    PIT.lock().control.read() & CHANNEL_2_OUTPUT != 0
+}
+
+/// Spin for `micros` microseconds. Doesn't need interrupts, so it's
+/// usable anywhere, but nothing else runs.
+pub fn busy_wait_micros(micros: u64)
+{
+    let mut left = micros;
+    while left > 0 {
+        let chunk = if left > MAX_ONE_SHOT_MICROS as u64 {
+            MAX_ONE_SHOT_MICROS
+        } else {
+            left as u32
+        };
+        start_one_shot(chunk);
+        while !one_shot_expired() {}
+        left -= chunk as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{divisor_for, ticks_for_nanos};
+    use time::NANOS_PER_SECOND;
+
+    #[test]
+    fn divisors()
+    {
+        assert_eq!(divisor_for(100), 11931);
+        assert_eq!(divisor_for(1000), 1193);
+        // Slower than a full count, or faster than mode 2 can go.
+        assert_eq!(divisor_for(18), 0x10000);
+        assert_eq!(divisor_for(1), 0x10000);
+        assert_eq!(divisor_for(596_591), 2);
+        assert_eq!(divisor_for(1_000_000), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_frequency()
+    {
+        divisor_for(0);
+    }
+
+    // Nothing calls `init` in tests, so ticks are the firmware's 65536
+    // cycles, 54925401.9 ns.
+    #[test]
+    fn ticks_round_up()
+    {
+        assert_eq!(ticks_for_nanos(0), 0);
+        assert_eq!(ticks_for_nanos(1), 1);
+        assert_eq!(ticks_for_nanos(54_925_401), 1);
+        assert_eq!(ticks_for_nanos(54_925_402), 2);
+        assert_eq!(ticks_for_nanos(NANOS_PER_SECOND), 19);
+        assert_eq!(ticks_for_nanos(10 * NANOS_PER_SECOND), 183);
+    }
+}
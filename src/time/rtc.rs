@@ -0,0 +1,346 @@
+
+//! The CMOS real time clock.
+//!
+//! Only read once at boot for the wall clock time, after that the
+//! monotonic clock is added on. It can also raise IRQ 8 periodically
+//! or at an alarm time. The RTC is assumed to be in UTC, and in the
+//! years 2000-2099 as the century register isn't read.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use irq::{self, deferred};
+use port::Port;
+use spin::IrqMutex;
+use super::{clock, NANOS_PER_SECOND};
+
+const RTC_IRQ: u8 = 8;
+
+// Registers.
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+// Status A.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RATE_MASK: u8 = 0x0f;
+
+// Status B.
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+
+// Status C, which interrupts fired. Reading it acknowledges them.
+const ALARM_FLAG: u8 = 1 << 5;
+const PERIODIC_FLAG: u8 = 1 << 6;
+
+/// In 12 hour mode the top bit of the hour is PM.
+const HOUR_PM: u8 = 1 << 7;
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8
+    {
+        self.index.write(register);
+        self.data.read()
+    }
+
+    fn write(&mut self, register: u8, value: u8)
+    {
+        self.index.write(register);
+        self.data.write(value);
+    }
+}
+
+static CMOS: IrqMutex<Cmos> = IrqMutex::new(Cmos {
+    index: unsafe { Port::new(0x70) },
+    data: unsafe { Port::new(0x71) },
+});
+
+static PERIODIC_COUNT: AtomicUsize = AtomicUsize::new(0);
+static ALARM: IrqMutex<Option<deferred::WorkFn>> = IrqMutex::new(None);
+
+ro_after_init! {
+    /// Unix time at boot, in nanoseconds.
+    static mut BOOT_TIME: u64 = 0;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn to_unix(&self) -> u64
+    {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 +
+            self.second as u64
+    }
+
+    pub fn from_unix(seconds: u64) -> DateTime
+    {
+        let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+        let time = seconds % 86400;
+        DateTime {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// Days since 1970-01-01. From Howard Hinnant's date algorithms.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64
+{
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64)
+{
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn from_bcd(value: u8) -> u8
+{
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+fn to_bcd(value: u8) -> u8
+{
+    (value / 10) << 4 | value % 10
+}
+
+/// The raw time registers.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Raw([u8; 6]);
+
+fn read_raw(cmos: &mut Cmos) -> Raw
+{
+    while cmos.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    Raw([cmos.read(SECONDS), cmos.read(MINUTES), cmos.read(HOURS),
+         cmos.read(DAY), cmos.read(MONTH), cmos.read(YEAR)])
+}
+
+/// Convert an hour register to 0-23.
+fn decode_hour(raw: u8, status_b: u8) -> u8
+{
+    let pm = raw & HOUR_PM != 0;
+    let hour = raw & !HOUR_PM;
+    let hour = if status_b & BINARY == 0 { from_bcd(hour) } else { hour };
+    if status_b & HOUR_24 != 0 {
+        hour
+    } else {
+        // 12 AM is midnight, 12 PM is noon.
+        hour % 12 + if pm { 12 } else { 0 }
+    }
+}
+
+/// The date and time from the RTC. Only the last two digits of the
+/// year are read, so it's always taken to be 2000-2099.
+pub fn read() -> DateTime
+{
+    let mut cmos = CMOS.lock();
+    // The registers can change under us even when not updating, so
+    // read until it's the same twice.
+    let mut raw = read_raw(&mut cmos);
+    loop {
+        let again = read_raw(&mut cmos);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status_b = cmos.read(STATUS_B);
+    let Raw([second, minute, hour, day, month, year]) = raw;
+
+    let decode = |value: u8| if status_b & BINARY == 0 { from_bcd(value) } else { value };
+    DateTime {
+        // No century register without looking in the FADT.
+        year: 2000 + decode(year) as u32,
+        month: decode(month),
+        day: decode(day),
+        hour: decode_hour(hour, status_b),
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Read the boot time and register for IRQ 8. The monotonic clock has
+/// to be set up, and it's only called during boot.
+pub fn init()
+{
+    let time = read();
+    unsafe {
+        BOOT_TIME = (time.to_unix() * NANOS_PER_SECOND).saturating_sub(clock::now());
+    }
+    // Clear anything left pending or it never interrupts again.
+    CMOS.lock().read(STATUS_C);
+    irq::register(RTC_IRQ, interrupt).expect("IRQ 8 is taken");
+}
+
+/// Unix time in nanoseconds. Only right between 2000 and 2099, see
+/// `read`.
+pub fn wall_clock() -> u64
+{
+    unsafe { BOOT_TIME } + clock::now()
+}
+
+pub fn wall_clock_time() -> DateTime
+{
+    DateTime::from_unix(wall_clock() / NANOS_PER_SECOND)
+}
+
+fn interrupt()
+{
+    let flags = CMOS.lock().read(STATUS_C);
+    if flags & PERIODIC_FLAG != 0 {
+        PERIODIC_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    if flags & ALARM_FLAG != 0 {
+        if let Some(alarm) = *ALARM.lock() {
+            deferred::defer(alarm, 0).ok();
+        }
+    }
+}
+
+/// Interrupt at `32768 >> (rate - 1)` Hz, rate is 3 (8192 Hz) to 15
+/// (2 Hz). 0 turns it off.
+pub fn set_periodic(rate: u8)
+{
+    assert!(rate == 0 || (rate >= 3 && rate <= 15), "invalid RTC rate {}", rate);
+    let mut cmos = CMOS.lock();
+    let status_a = cmos.read(STATUS_A);
+    cmos.write(STATUS_A, (status_a & !RATE_MASK) | rate);
+    let status_b = cmos.read(STATUS_B);
+    if rate == 0 {
+        cmos.write(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+    } else {
+        cmos.write(STATUS_B, status_b | PERIODIC_INTERRUPT);
+    }
+}
+
+/// Periodic interrupts since boot.
+pub fn periodic_count() -> usize
+{
+    PERIODIC_COUNT.load(Ordering::Relaxed)
+}
+
+/// Call `callback` once a day at `hour:minute:second` UTC, as deferred
+/// work. Replaces any earlier alarm.
+pub fn set_alarm(hour: u8, minute: u8, second: u8, callback: deferred::WorkFn)
+{
+    assert!(hour < 24 && minute < 60 && second < 60, "invalid alarm time");
+    *ALARM.lock() = Some(callback);
+
+    let mut cmos = CMOS.lock();
+    let status_b = cmos.read(STATUS_B);
+    let encode = |value: u8| if status_b & BINARY == 0 { to_bcd(value) } else { value };
+    let hour = if status_b & HOUR_24 != 0 {
+        encode(hour)
+    } else {
+        let twelve = if hour % 12 == 0 { 12 } else { hour % 12 };
+        encode(twelve) | if hour >= 12 { HOUR_PM } else { 0 }
+    };
+    cmos.write(SECONDS_ALARM, encode(second));
+    cmos.write(MINUTES_ALARM, encode(minute));
+    cmos.write(HOURS_ALARM, hour);
+    cmos.write(STATUS_B, status_b | ALARM_INTERRUPT);
+}
+
+pub fn clear_alarm()
+{
+    let mut cmos = CMOS.lock();
+    let status_b = cmos.read(STATUS_B);
+    cmos.write(STATUS_B, status_b & !ALARM_INTERRUPT);
+    *ALARM.lock() = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{days_from_civil, civil_from_days, decode_hour, BINARY, HOUR_24, HOUR_PM};
+
+    #[test]
+    fn days()
+    {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 1, 1), 10957);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2099, 12, 31), 47481);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+    }
+
+    #[test]
+    fn civil_round_trip()
+    {
+        for &days in &[-1, 0, 10956, 10957, 11016, 11017, 47481, 47482] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // 2000 is a leap year, 2100 isn't.
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(days_from_civil(2100, 3, 1) - 1), (2100, 2, 28));
+    }
+
+    #[test]
+    fn hours()
+    {
+        // BCD, 24 hour.
+        assert_eq!(decode_hour(0x23, HOUR_24), 23);
+        assert_eq!(decode_hour(0x00, HOUR_24), 0);
+        // Binary, 24 hour.
+        assert_eq!(decode_hour(17, HOUR_24 | BINARY), 17);
+        // BCD, 12 hour.
+        assert_eq!(decode_hour(0x12, 0), 0);
+        assert_eq!(decode_hour(0x12 | HOUR_PM, 0), 12);
+        assert_eq!(decode_hour(0x01 | HOUR_PM, 0), 13);
+        assert_eq!(decode_hour(0x11, 0), 11);
+        // Binary, 12 hour.
+        assert_eq!(decode_hour(11 | HOUR_PM, BINARY), 23);
+    }
+}
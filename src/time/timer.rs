@@ -0,0 +1,397 @@
+
+//! Kernel timers.
+//!
+//! Pending timers sit in a hierarchical timing wheel that turns once
+//! per PIT tick, so adding and cancelling are cheap however far off the
+//! deadline is. Level 0 has a slot per tick, each level above has a
+//! slot per whole turn of the one below, and timers are moved down a
+//! level as their deadline gets close. Callbacks run as deferred work,
+//! not in the tick interrupt.
+
+use core::cmp;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cpu;
+use irq::deferred::{self, WorkFn};
+use spin::IrqMutex;
+use super::{clock, pit};
+
+/// Most timers pending at once.
+const MAX_TIMERS: usize = 64;
+const LEVELS: usize = 4;
+const SLOT_BITS: u64 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+/// Furthest ahead the wheel reaches, about 46 hours at 100 Hz. Later
+/// timers wait in the top level and are placed again as it turns.
+const MAX_DELTA: u64 = (1 << (SLOT_BITS * LEVELS as u64)) - 1;
+/// The list of timers that are due but haven't run yet, after the
+/// wheel's slots.
+const EXPIRED: usize = LEVELS * SLOTS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyTimers;
+
+/// A pending timer, for `cancel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: usize,
+    /// Stops an old id cancelling whatever reused the entry.
+    generation: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// The tick it's due on.
+    expires: u64,
+    callback: WorkFn,
+    data: usize,
+    /// The list it's on and the next entry on it.
+    list: usize,
+    next: Option<usize>,
+}
+
+struct Wheel {
+    /// Next tick to be processed.
+    current: u64,
+    lists: [Option<usize>; LEVELS * SLOTS + 1],
+    entries: [Option<Entry>; MAX_TIMERS],
+    generations: [usize; MAX_TIMERS],
+    count: usize,
+}
+
+impl Wheel {
+    /// The list for a timer due on tick `expires`.
+    fn list_for(&self, expires: u64) -> usize
+    {
+        // Anything overdue goes in the slot about to be processed.
+        let delta = cmp::min(expires.saturating_sub(self.current), MAX_DELTA);
+        let expires = self.current + delta;
+        let level = (0..LEVELS)
+            .find(|&level| delta < 1 << (SLOT_BITS * (level as u64 + 1)))
+            .unwrap();
+        let slot = (expires >> (SLOT_BITS * level as u64)) & SLOT_MASK;
+        level * SLOTS + slot as usize
+    }
+
+    fn push(&mut self, index: usize, list: usize)
+    {
+        let head = self.lists[list];
+        if let Some(ref mut entry) = self.entries[index] {
+            entry.list = list;
+            entry.next = head;
+        }
+        self.lists[list] = Some(index);
+    }
+
+    fn unlink(&mut self, index: usize)
+    {
+        let (list, next) = match self.entries[index] {
+            Some(entry) => (entry.list, entry.next),
+            None => return,
+        };
+        if self.lists[list] == Some(index) {
+            self.lists[list] = next;
+            return;
+        }
+        let mut current = self.lists[list];
+        while let Some(i) = current {
+            let entry = self.entries[i].as_mut().expect("free timer on a list");
+            if entry.next == Some(index) {
+                entry.next = next;
+                return;
+            }
+            current = entry.next;
+        }
+    }
+
+    fn add(&mut self, expires: u64, callback: WorkFn, data: usize)
+           -> Result<TimerId, TooManyTimers>
+    {
+        let index = try!(self.entries.iter().position(|e| e.is_none()).ok_or(TooManyTimers));
+        if self.count == 0 {
+            // Nothing to miss, so skip the ticks it sat empty for.
+            self.current = pit::ticks();
+        }
+        self.entries[index] = Some(Entry {
+            expires: expires,
+            callback: callback,
+            data: data,
+            list: 0,
+            next: None,
+        });
+        let list = self.list_for(expires);
+        self.push(index, list);
+        self.count += 1;
+        Ok(TimerId { index: index, generation: self.generations[index] })
+    }
+
+    fn remove(&mut self, index: usize) -> Entry
+    {
+        self.unlink(index);
+        self.generations[index] += 1;
+        self.count -= 1;
+        self.entries[index].take().expect("removing a free timer")
+    }
+
+    /// Place every timer in slot `current` of `level` again, now it's
+    /// within reach of the level below. Returns the slot.
+    fn cascade(&mut self, level: usize) -> usize
+    {
+        let slot = ((self.current >> (SLOT_BITS * level as u64)) & SLOT_MASK) as usize;
+        let mut next = self.lists[level * SLOTS + slot].take();
+        while let Some(index) = next {
+            let entry = self.entries[index].expect("free timer on a list");
+            next = entry.next;
+            let list = self.list_for(entry.expires);
+            self.push(index, list);
+        }
+        slot
+    }
+
+    /// Process tick `current`, its timers go on the expired list.
+    fn advance(&mut self)
+    {
+        let slot = (self.current & SLOT_MASK) as usize;
+        // Level 0 went round, so refill it from above. Each level only
+        // cascades when the one below has also gone round.
+        if slot == 0 {
+            for level in 1..LEVELS {
+                if self.cascade(level) != 0 {
+                    break;
+                }
+            }
+        }
+        let mut next = self.lists[slot].take();
+        while let Some(index) = next {
+            next = self.entries[index].expect("free timer on a list").next;
+            self.push(index, EXPIRED);
+        }
+        self.current += 1;
+    }
+
+    /// Take the next timer due by tick `now`.
+    fn pop_expired(&mut self, now: u64) -> Option<Entry>
+    {
+        while self.lists[EXPIRED].is_none() && self.current <= now && self.count > 0 {
+            self.advance();
+        }
+        match self.lists[EXPIRED] {
+            Some(index) => Some(self.remove(index)),
+            None => None,
+        }
+    }
+}
+
+static WHEEL: IrqMutex<Wheel> = IrqMutex::new(Wheel {
+    current: 0,
+    lists: [None; LEVELS * SLOTS + 1],
+    entries: [None; MAX_TIMERS],
+    generations: [0; MAX_TIMERS],
+    count: 0,
+});
+
+/// `run_timers` is already queued, so ticks don't flood the deferred
+/// queue when it's slow to run.
+static QUEUED: AtomicBool = AtomicBool::new(false);
+
+/// Call `callback` with `data`, as deferred work, once `clock::now()`
+/// reaches `deadline`. It's rounded up to a PIT tick, so timers have
+/// the tick's resolution.
+pub fn add_timer(deadline: u64, callback: WorkFn, data: usize) -> Result<TimerId, TooManyTimers>
+{
+    // The clock may not be the PIT, so only the time left is converted.
+    // Part of the current tick has already gone, hence the extra one.
+    let delta = deadline.saturating_sub(clock::now());
+    let expires = pit::ticks() + pit::ticks_for_nanos(delta) + 1;
+    WHEEL.lock().add(expires, callback, data)
+}
+
+/// Stop a timer. Returns false if it's already run, or started running,
+/// or was cancelled before.
+pub fn cancel(id: TimerId) -> bool
+{
+    let mut wheel = WHEEL.lock();
+    if wheel.generations[id.index] != id.generation || wheel.entries[id.index].is_none() {
+        return false;
+    }
+    wheel.remove(id.index);
+    true
+}
+
+/// Timers waiting to run.
+pub fn pending() -> usize
+{
+    WHEEL.lock().count
+}
+
+/// Called from the PIT interrupt every tick.
+pub fn tick()
+{
+    if WHEEL.lock().count == 0 || QUEUED.swap(true, Ordering::Acquire) {
+        return;
+    }
+    if deferred::defer(run_timers, 0).is_err() {
+        // Try again next tick.
+        QUEUED.store(false, Ordering::Release);
+    }
+}
+
+fn run_timers(_: usize)
+{
+    QUEUED.store(false, Ordering::Release);
+    let now = pit::ticks();
+    loop {
+        // Not locked while the callback runs, so it can add timers.
+        let expired = WHEEL.lock().pop_expired(now);
+        match expired {
+            Some(entry) => (entry.callback)(entry.data),
+            None => break,
+        }
+    }
+}
+
+fn wake(woken: usize)
+{
+    let woken = unsafe { &*(woken as *const AtomicBool) };
+    woken.store(true, Ordering::Release);
+}
+
+/// Wait `nanos` nanoseconds. There's no scheduler yet, so this halts
+/// until a timer wakes it. Interrupts have to be enabled, and it can't
+/// be used from deferred work, timer callbacks included, as the wakeup
+/// is deferred work too.
+pub fn sleep(nanos: u64)
+{
+    assert!(cpu::interrupts_enabled(), "sleep with interrupts disabled");
+    assert!(!deferred::is_running(), "sleep from deferred work never wakes");
+    let deadline = clock::now().saturating_add(nanos);
+    let woken = AtomicBool::new(false);
+    if add_timer(deadline, wake, &woken as *const AtomicBool as usize).is_err() {
+        // No timer to wake us, so watch the clock instead.
+        while clock::now() < deadline {
+            cpu::halt_unless(|| clock::now() >= deadline);
+        }
+        return;
+    }
+    while !woken.load(Ordering::Acquire) {
+        cpu::halt_unless(|| woken.load(Ordering::Acquire));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Wheel, LEVELS, SLOTS, MAX_TIMERS, EXPIRED};
+
+    fn nothing(_: usize) {}
+
+    fn wheel() -> Wheel
+    {
+        Wheel {
+            current: 0,
+            lists: [None; LEVELS * SLOTS + 1],
+            entries: [None; MAX_TIMERS],
+            generations: [0; MAX_TIMERS],
+            count: 0,
+        }
+    }
+
+    /// The data of the timers due by `now`, in the order they come.
+    fn expired(wheel: &mut Wheel, now: u64) -> Vec<usize>
+    {
+        let mut data = Vec::new();
+        while let Some(entry) = wheel.pop_expired(now) {
+            assert!(entry.expires <= now, "timer for {} ran at {}", entry.expires, now);
+            data.push(entry.data);
+        }
+        data
+    }
+
+    #[test]
+    fn lists()
+    {
+        let mut wheel = wheel();
+        assert_eq!(wheel.list_for(0), 0);
+        assert_eq!(wheel.list_for(63), 63);
+        // Level 1 slots are 64 ticks each.
+        assert_eq!(wheel.list_for(64), SLOTS + 1);
+        assert_eq!(wheel.list_for(4095), SLOTS + 63);
+        assert_eq!(wheel.list_for(4096), 2 * SLOTS + 1);
+        // Too far off waits at the end of the top level.
+        assert_eq!(wheel.list_for(!0), 3 * SLOTS + 63);
+
+        wheel.current = 100;
+        // Overdue goes in the slot about to be processed.
+        assert_eq!(wheel.list_for(50), 100 % SLOTS);
+        assert_eq!(wheel.list_for(101), 101 % SLOTS);
+        assert_eq!(wheel.list_for(164), SLOTS + 2);
+    }
+
+    #[test]
+    fn advance_expires_each_tick()
+    {
+        let mut wheel = wheel();
+        wheel.add(3, nothing, 3).unwrap();
+        wheel.add(5, nothing, 5).unwrap();
+        wheel.advance();
+        wheel.advance();
+        wheel.advance();
+        assert_eq!(wheel.lists[EXPIRED], None);
+        wheel.advance();
+        assert_eq!(wheel.entries[wheel.lists[EXPIRED].unwrap()].unwrap().data, 3);
+        assert_eq!(wheel.current, 4);
+    }
+
+    #[test]
+    fn cascades()
+    {
+        let mut wheel = wheel();
+        for &expires in &[3, 64, 65, 70, 4095, 4096, 5000, 300_000] {
+            wheel.add(expires, nothing, expires as usize).unwrap();
+        }
+        assert_eq!(expired(&mut wheel, 2), vec![]);
+        assert_eq!(expired(&mut wheel, 3), vec![3]);
+        assert_eq!(expired(&mut wheel, 63), vec![]);
+        assert_eq!(expired(&mut wheel, 64), vec![64]);
+        assert_eq!(expired(&mut wheel, 70), vec![65, 70]);
+        assert_eq!(expired(&mut wheel, 4095), vec![4095]);
+        assert_eq!(expired(&mut wheel, 4999), vec![4096]);
+        assert_eq!(expired(&mut wheel, 5000), vec![5000]);
+        assert_eq!(expired(&mut wheel, 299_999), vec![]);
+        assert_eq!(expired(&mut wheel, 300_000), vec![300_000]);
+        assert_eq!(wheel.count, 0);
+    }
+
+    #[test]
+    fn cascade_returns_the_slot()
+    {
+        let mut wheel = wheel();
+        wheel.add(130, nothing, 0).unwrap();
+        wheel.current = 128;
+        assert_eq!(wheel.cascade(1), 2);
+        assert_eq!(wheel.lists[SLOTS + 2], None);
+        assert_eq!(wheel.lists[130 % SLOTS], Some(0));
+    }
+
+    #[test]
+    fn remove_from_the_middle()
+    {
+        let mut wheel = wheel();
+        let ids: Vec<_> = (0..3).map(|data| wheel.add(10, nothing, data).unwrap()).collect();
+        wheel.remove(ids[1].index);
+        assert_eq!(wheel.count, 2);
+        assert_eq!(wheel.generations[ids[1].index], 1);
+        let mut data = expired(&mut wheel, 10);
+        data.sort();
+        assert_eq!(data, vec![0, 2]);
+    }
+
+    #[test]
+    fn full()
+    {
+        let mut wheel = wheel();
+        for data in 0..MAX_TIMERS {
+            wheel.add(1, nothing, data).unwrap();
+        }
+        assert!(wheel.add(1, nothing, 0).is_err());
+    }
+}
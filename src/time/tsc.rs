@@ -0,0 +1,97 @@
+
+//! The time stamp counter.
+//!
+//! Calibrated against a PIT one-shot. It's only used as a clock if it's
+//! invariant, older CPUs change its rate with the clock speed.
+
+use cpu;
+use super::clock::{self, ClockSource};
+use super::pit;
+use x86::irq::{enable, disable};
+
+/// How long each calibration run is.
+const CALIBRATION_MICROS: u32 = 50_000;
+/// Odd, so there's a middle one.
+const CALIBRATION_RUNS: usize = 5;
+
+static mut FREQUENCY: u64 = 0;
+
+/// CPUID says the TSC runs at a constant rate, whatever the P-, C- and
+/// T-state.
+pub fn is_invariant() -> bool
+{
+    cpu::cpuid(0x8000_0000, 0).eax >= 0x8000_0007 &&
+        cpu::cpuid(0x8000_0007, 0).edx & (1 << 8) != 0
+}
+
+/// Measure the TSC frequency against the PIT. Returns it in Hz.
+///
+/// Interrupts are off for the whole thing, a quarter of a second, so
+/// call it before `pit::init` or the ticks in that time are lost.
+pub fn calibrate() -> u64
+{
+    // An interrupt in the middle would count towards the TSC but not
+    // the PIT.
+    let enabled = cpu::interrupts_enabled();
+    unsafe { disable() };
+
+    let mut runs = [0; CALIBRATION_RUNS];
+    for run in runs.iter_mut() {
+        pit::start_one_shot(CALIBRATION_MICROS);
+        let start = cpu::rdtsc();
+        while !pit::one_shot_expired() {}
+        *run = cpu::rdtsc() - start;
+    }
+
+    if enabled {
+        unsafe { enable() };
+    }
+
+    // The start is read a little after the PIT starts counting, and
+    // SMIs can land in a run, so go with the median.
+    for i in 1..CALIBRATION_RUNS {
+        let mut j = i;
+        while j > 0 && runs[j - 1] > runs[j] {
+            runs.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+    let cycles = runs[CALIBRATION_RUNS / 2];
+
+    let frequency = cycles * 1_000_000 / CALIBRATION_MICROS as u64;
+    unsafe { FREQUENCY = frequency };
+    frequency
+}
+
+/// In Hz, 0 before `calibrate`.
+pub fn frequency() -> u64
+{
+    unsafe { FREQUENCY }
+}
+
+/// Convert a number of cycles to nanoseconds. Needs `calibrate`.
+pub fn cycles_to_nanos(cycles: u64) -> u64
+{
+    let frequency = frequency();
+    assert!(frequency != 0, "TSC used before calibration");
+    clock::to_nanos(cycles, frequency)
+}
+
+pub struct TscClock;
+
+impl ClockSource for TscClock {
+    fn name(&self) -> &'static str
+    {
+        "TSC"
+    }
+
+    fn read(&self) -> u64
+    {
+        cpu::rdtsc()
+    }
+
+    fn frequency(&self) -> u64
+    {
+        frequency()
+    }
+}
@@ -1,6 +1,10 @@
 
 use core::ptr::Unique;
-use spin::Mutex;
+use spin::IrqMutex;
+use memory::KERNEL_OFFSET;
+
+/// Physical address of the VGA text buffer.
+pub const VGA_BUFFER: usize = 0xb8000;
 
 macro_rules! println {
     ($fmt:expr) => (print!(concat!($fmt, "\n")));
@@ -71,10 +75,10 @@ pub struct Writer {
     buffer: Unique<Buffer>,
 }
 
-pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
+pub static WRITER: IrqMutex<Writer> = IrqMutex::new(Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::LightGreen, Color::Black),
-    buffer: unsafe { Unique::new(0xb8000 as *mut _) },
+    buffer: unsafe { Unique::new((KERNEL_OFFSET + VGA_BUFFER) as *mut _) },
 });
 
 impl Writer {
@@ -151,3 +155,14 @@ impl ::core::fmt::Write for Writer {
         Ok(())
     }
 }
+
+/// Print unless someone else has the screen, in which case it's dropped.
+/// For NMIs, machine checks and debug exceptions, which can interrupt
+/// the lock holder.
+pub fn try_print(args: ::core::fmt::Arguments)
+{
+    use core::fmt::Write;
+    if let Some(mut writer) = WRITER.try_lock() {
+        writer.write_fmt(args).ok();
+    }
+}
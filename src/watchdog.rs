@@ -0,0 +1,169 @@
+
+//! Hard lockup detection.
+//!
+//! A performance counter counting unhalted cycles raises an NMI every
+//! `PERIOD` cycles. Each one checks the heartbeat bumped by the timer
+//! interrupt, IRQ 0. If it hasn't moved for enough NMIs the CPU has
+//! been running with interrupts off all that time. Cycles aren't
+//! counted while halted, so an idle CPU never trips it.
+//!
+//! Enough is `MISSED_TICKS` of the PIT's ticks, at whatever rate it was
+//! set to, turned into NMIs with the TSC rate standing in for the cycle
+//! rate. Nothing is checked until the first tick.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use cpu;
+use debug::Backtrace;
+use irq;
+use irq::apic::{APIC, LocalApic};
+use irq::isr::InterruptStack;
+use time::{pit, tsc};
+use x86::msr::{rdmsr, wrmsr};
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+const EVENT_UNHALTED_CORE_CYCLES: u64 = 0x3c;
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_ENABLE: u64 = 1 << 22;
+
+/// Cycles between NMIs. PMC writes only take 32 bits, sign extended,
+/// so it has to be below 2^31.
+const PERIOD: u64 = 1 << 30;
+/// Timer ticks that can go missing before it's a lockup.
+const MISSED_TICKS: u64 = 200;
+/// Fewest NMIs without a heartbeat before it's a lockup, one could
+/// land just after a tick.
+const MIN_THRESHOLD: u64 = 2;
+/// Used if the TSC rate isn't known.
+const DEFAULT_THRESHOLD: usize = 10;
+
+const TIMER_IRQ: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// The NMI is delivered through the local APIC.
+    NoApic,
+    /// No architectural performance counters, or no cycle event.
+    NoPerfmon,
+    /// Couldn't get a handler on the timer IRQ for the heartbeat.
+    NoTimer,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static HEARTBEAT: AtomicUsize = AtomicUsize::new(0);
+
+// Only touched from the NMI handler, NMIs don't nest.
+static mut LAST_HEARTBEAT: usize = 0;
+static mut STALE: usize = 0;
+/// A copy so the NMI handler doesn't need the `APIC` lock.
+static mut LOCAL_APIC: Option<LocalApic> = None;
+static mut PERFMON_VERSION: u8 = 0;
+
+ro_after_init! {
+    /// NMIs without a heartbeat before it's a lockup.
+    static mut THRESHOLD: usize = DEFAULT_THRESHOLD;
+}
+
+/// The timer interrupt's handler, says interrupts are getting through.
+pub fn touch()
+{
+    HEARTBEAT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Start the performance counter. Needs the APIC to be set up.
+pub fn init() -> Result<(), WatchdogError>
+{
+    let local = match *APIC.lock() {
+        Some(ref apic) => *apic.local(),
+        None => return Err(WatchdogError::NoApic),
+    };
+    if cpu::max_leaf() < 0xa {
+        return Err(WatchdogError::NoPerfmon);
+    }
+    let perfmon = cpu::cpuid(0xa, 0);
+    let version = perfmon.eax as u8;
+    let counters = (perfmon.eax >> 8) as u8;
+    // ebx bit 0 set means the core cycles event isn't there.
+    if version == 0 || counters == 0 || perfmon.ebx & 1 != 0 {
+        return Err(WatchdogError::NoPerfmon);
+    }
+    try!(irq::register(TIMER_IRQ, touch).map_err(|_| WatchdogError::NoTimer));
+
+    unsafe {
+        THRESHOLD = threshold();
+        LOCAL_APIC = Some(local);
+        PERFMON_VERSION = version;
+        local.set_perf_nmi();
+        wrmsr(IA32_PERFEVTSEL0, 0);
+        reload_counter();
+        wrmsr(IA32_PERFEVTSEL0, EVENT_UNHALTED_CORE_CYCLES | EVTSEL_USR | EVTSEL_OS |
+              EVTSEL_INT | EVTSEL_ENABLE);
+        if version >= 2 {
+            wrmsr(IA32_PERF_GLOBAL_CTRL, rdmsr(IA32_PERF_GLOBAL_CTRL) | 1);
+        }
+    }
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// NMIs that cover `MISSED_TICKS` ticks.
+fn threshold() -> usize
+{
+    let cycles_per_second = tsc::frequency();
+    if cycles_per_second == 0 {
+        return DEFAULT_THRESHOLD;
+    }
+    let timeout_millis = MISSED_TICKS * 1_000_000 / pit::frequency_millihertz();
+    let cycles = timeout_millis * (cycles_per_second / 1000);
+    let nmis = (cycles + PERIOD - 1) / PERIOD;
+    if nmis < MIN_THRESHOLD { MIN_THRESHOLD as usize } else { nmis as usize }
+}
+
+unsafe fn reload_counter()
+{
+    wrmsr(IA32_PMC0, PERIOD.wrapping_neg());
+}
+
+/// Called for every NMI. Returns true if it was the watchdog's.
+pub fn nmi(stack: &InterruptStack) -> bool
+{
+    if !ENABLED.load(Ordering::Acquire) {
+        return false;
+    }
+    // The counter starts negative, it's only non-negative once it's
+    // overflowed.
+    if unsafe { rdmsr(IA32_PMC0) } & (1 << 31) != 0 {
+        return false;
+    }
+
+    unsafe {
+        reload_counter();
+        if PERFMON_VERSION >= 2 {
+            wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1);
+        }
+        // Delivering the NMI masks the LVT entry.
+        if let Some(local) = LOCAL_APIC {
+            local.set_perf_nmi();
+        }
+    }
+
+    let heartbeat = HEARTBEAT.load(Ordering::Relaxed);
+    unsafe {
+        if heartbeat == 0 || heartbeat != LAST_HEARTBEAT {
+            LAST_HEARTBEAT = heartbeat;
+            STALE = 0;
+        } else {
+            STALE += 1;
+            if STALE >= THRESHOLD {
+                panic!("Hard lockup: no timer interrupts for {} watchdog NMIs\n{}{}",
+                       STALE, stack, Backtrace::new(stack.registers.rbp));
+            }
+        }
+    }
+    true
+}